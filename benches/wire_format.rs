@@ -0,0 +1,127 @@
+//! Compares the JSON and MessagePack wire formats for the messages sent through the tunnel.
+//!
+//! Run with `cargo bench --bench wire_format`; the encoded sizes are printed before the timings.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::RngCore;
+use uuid::Uuid;
+
+use request_proxy::protocol::WireFormat;
+use request_proxy::types::{Base64Bytes, ClientResponse, ProxiedRequest, RequestUri};
+
+const FORMATS: [WireFormat; 2] = [WireFormat::Json, WireFormat::MessagePack];
+
+/// Bodies of a typical API response, and of a large binary download
+fn bodies() -> Vec<(&'static str, Vec<u8>)> {
+    let mut binary = vec![0; 1024 * 1024];
+    rand::thread_rng().fill_bytes(&mut binary);
+
+    vec![
+        ("1KiB text", br#"{"id":1,"name":"example"},"#.repeat(40)),
+        ("1MiB binary", binary),
+    ]
+}
+
+fn request(body: &[u8]) -> ProxiedRequest<'static> {
+    ProxiedRequest {
+        method: "POST",
+        uri: RequestUri {
+            path: String::from("/api/upload"),
+            query: Some(String::from("draft=true")),
+            fragment: None,
+        },
+        version: String::from("HTTP/1.1"),
+        headers: vec![
+            ("host", Base64Bytes(b"example.test".to_vec())),
+            (
+                "content-type",
+                Base64Bytes(b"application/octet-stream".to_vec()),
+            ),
+            (
+                "user-agent",
+                Base64Bytes(b"Mozilla/5.0 (X11; Linux x86_64)".to_vec()),
+            ),
+        ],
+        body: Base64Bytes(body.to_vec()),
+        id: Uuid::new_v4(),
+        remote_addr: Some([192, 0, 2, 1].into()),
+        scheme: Some(String::from("https")),
+        host: Some(String::from("example.test")),
+    }
+}
+
+fn response(body: &[u8]) -> ClientResponse {
+    ClientResponse {
+        request_id: Uuid::new_v4(),
+        status: 200,
+        headers: vec![
+            (
+                String::from("content-type"),
+                Base64Bytes(b"application/octet-stream".to_vec()),
+            ),
+            (
+                String::from("cache-control"),
+                Base64Bytes(b"no-store".to_vec()),
+            ),
+        ],
+        body: Base64Bytes(body.to_vec()),
+    }
+}
+
+fn wire_formats(c: &mut Criterion) {
+    for (name, body) in bodies() {
+        let request = request(&body);
+        let response = response(&body);
+
+        for format in FORMATS {
+            println!(
+                "{:?} with a {} body: request {} bytes, response {} bytes",
+                format,
+                name,
+                format.encode(&request).len(),
+                format.encode(&response).len()
+            );
+        }
+
+        let mut group = c.benchmark_group(format!("ProxiedRequest/{}", name));
+        group.throughput(Throughput::Bytes(body.len() as u64));
+
+        for format in FORMATS {
+            let encoded = format.encode(&request);
+
+            group.bench_function(BenchmarkId::new("encode", format!("{:?}", format)), |b| {
+                b.iter(|| format.encode(black_box(&request)))
+            });
+            group.bench_function(BenchmarkId::new("decode", format!("{:?}", format)), |b| {
+                b.iter(|| {
+                    format
+                        .decode::<ProxiedRequest>(black_box(&encoded))
+                        .unwrap()
+                })
+            });
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("ClientResponse/{}", name));
+        group.throughput(Throughput::Bytes(body.len() as u64));
+
+        for format in FORMATS {
+            let encoded = format.encode(&response);
+
+            group.bench_function(BenchmarkId::new("encode", format!("{:?}", format)), |b| {
+                b.iter(|| format.encode(black_box(&response)))
+            });
+            group.bench_function(BenchmarkId::new("decode", format!("{:?}", format)), |b| {
+                b.iter(|| {
+                    format
+                        .decode::<ClientResponse>(black_box(&encoded))
+                        .unwrap()
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, wire_formats);
+criterion_main!(benches);
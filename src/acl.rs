@@ -0,0 +1,158 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses in CIDR notation, eg: `192.168.0.0/16` or `2001:db8::/32`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Treat IPv4 addresses mapped into IPv6 (::ffff:a.b.c.d) as the IPv4 address they are
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix_len` bits of `a` and `b` are equal
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    let remaining_bits = prefix_len % 8;
+
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - remaining_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    /// Parse a CIDR block; a lone address is treated as a block containing only itself
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s.trim(), None),
+        };
+
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("Invalid address in '{}': {}", s, e))?
+            .to_canonical();
+
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(p) => u8::from_str(p)
+                .ok()
+                .filter(|p| *p <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length in '{}'", s))?,
+            None => max_prefix_len,
+        };
+
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Decides which visitor addresses are allowed through the tunnel
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    /// If not empty, only addresses within these networks are allowed
+    pub allow: Vec<IpNetwork>,
+
+    /// Addresses within these networks are never allowed, even if they're also in `allow`
+    pub deny: Vec<IpNetwork>,
+}
+
+impl AccessList {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn net(s: &str) -> IpNetwork {
+        IpNetwork::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn network_contains() {
+        assert!(net("10.0.0.0/8").contains(ip("10.200.3.4")));
+        assert!(!net("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(net("192.168.4.0/22").contains(ip("192.168.7.255")));
+        assert!(!net("192.168.4.0/22").contains(ip("192.168.8.0")));
+        assert!(net("0.0.0.0/0").contains(ip("8.8.8.8")));
+        assert!(net("203.0.113.7").contains(ip("203.0.113.7")));
+        assert!(!net("203.0.113.7").contains(ip("203.0.113.8")));
+
+        assert!(net("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!net("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(!net("2001:db8::/32").contains(ip("10.0.0.1")));
+
+        // IPv4-mapped IPv6 addresses, as seen on dual-stack listeners
+        assert!(net("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn network_rejects_invalid_blocks() {
+        assert!(IpNetwork::from_str("10.0.0.0/33").is_err());
+        assert!(IpNetwork::from_str("2001:db8::/129").is_err());
+        assert!(IpNetwork::from_str("example.test/8").is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let acl = AccessList {
+            allow: vec![net("10.0.0.0/8")],
+            deny: vec![net("10.0.0.13")],
+        };
+
+        assert!(acl.is_allowed(ip("10.0.0.12")));
+        assert!(!acl.is_allowed(ip("10.0.0.13")));
+        assert!(!acl.is_allowed(ip("192.168.0.1")));
+
+        let deny_only = AccessList {
+            allow: vec![],
+            deny: vec![net("192.168.0.0/16")],
+        };
+
+        assert!(deny_only.is_allowed(ip("10.0.0.1")));
+        assert!(!deny_only.is_allowed(ip("192.168.0.1")));
+    }
+}
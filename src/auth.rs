@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Once this many keys are being tracked, ones which are no longer relevant are dropped
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Compare a presented secret with the expected one without leaking, through timing, how
+/// much of it was right.
+///
+/// Both are hashed first so that the comparison doesn't reveal the expected length either.
+pub fn secrets_match(presented: &str, expected: &str) -> bool {
+    let presented = Sha256::digest(presented.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+
+    presented.ct_eq(&expected).into()
+}
+
+/// When to lock out someone who keeps failing to authenticate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lockout {
+    /// Number of consecutive failures allowed before locking out
+    pub max_failures: u32,
+
+    /// Length of the first lockout; each further failure doubles it
+    pub duration: Duration,
+
+    /// Longest a single lockout may last
+    pub max_duration: Duration,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts authentication failures for each key, locking out keys with too many of them
+pub struct FailureTracker<K> {
+    lockout: Lockout,
+    failures: HashMap<K, Failures>,
+}
+
+impl<K: Hash + Eq> FailureTracker<K> {
+    pub fn new(lockout: Lockout) -> FailureTracker<K> {
+        FailureTracker {
+            lockout,
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Check whether `key` may attempt to authenticate, or how long until it may
+    pub fn check(&self, key: &K, now: Instant) -> Result<(), Duration> {
+        match self.failures.get(key).and_then(|f| f.locked_until) {
+            Some(until) if until > now => Err(until - now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failed attempt, returning how long `key` is now locked out for, if at all
+    pub fn record_failure(&mut self, key: K, now: Instant) -> Option<Duration> {
+        if self.failures.len() >= PRUNE_THRESHOLD && !self.failures.contains_key(&key) {
+            self.prune(now);
+        }
+
+        let lockout = self.lockout;
+        let failures = self.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+
+        // Start counting afresh once the failures are long in the past
+        if now.saturating_duration_since(failures.last) > lockout.max_duration {
+            failures.count = 0;
+        }
+
+        failures.count += 1;
+        failures.last = now;
+
+        if failures.count < lockout.max_failures {
+            return None;
+        }
+
+        let doublings = (failures.count - lockout.max_failures).min(16);
+        let duration = (lockout.duration * 2u32.pow(doublings)).min(lockout.max_duration);
+
+        failures.locked_until = Some(now + duration);
+        Some(duration)
+    }
+
+    /// Forget the failures of a key which has authenticated successfully
+    pub fn record_success(&mut self, key: &K) {
+        self.failures.remove(key);
+    }
+
+    /// Forget keys whose failures have expired
+    fn prune(&mut self, now: Instant) {
+        let max_duration = self.lockout.max_duration;
+
+        self.failures.retain(|_, failures| {
+            failures.locked_until.is_some_and(|until| until > now)
+                || now.saturating_duration_since(failures.last) <= max_duration
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> FailureTracker<&'static str> {
+        FailureTracker::new(Lockout {
+            max_failures: 3,
+            duration: Duration::from_secs(10),
+            max_duration: Duration::from_secs(30),
+        })
+    }
+
+    #[test]
+    fn compares_secrets() {
+        assert!(secrets_match("hunter2", "hunter2"));
+        assert!(!secrets_match("hunter3", "hunter2"));
+        assert!(!secrets_match("hunter", "hunter2"));
+        assert!(!secrets_match("", "hunter2"));
+    }
+
+    #[test]
+    fn locks_out_after_repeated_failures() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+
+        assert_eq!(None, tracker.record_failure("a", start));
+        assert_eq!(None, tracker.record_failure("a", start));
+        assert_eq!(Ok(()), tracker.check(&"a", start));
+
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            tracker.record_failure("a", start)
+        );
+        assert_eq!(Err(Duration::from_secs(10)), tracker.check(&"a", start));
+        assert_eq!(Ok(()), tracker.check(&"b", start));
+
+        // Further failures back off exponentially, up to the limit
+        let later = start + Duration::from_secs(10);
+        assert_eq!(Ok(()), tracker.check(&"a", later));
+        assert_eq!(
+            Some(Duration::from_secs(20)),
+            tracker.record_failure("a", later)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            tracker.record_failure("a", later)
+        );
+    }
+
+    #[test]
+    fn success_and_time_reset_failures() {
+        let mut tracker = tracker();
+        let start = Instant::now();
+
+        tracker.record_failure("a", start);
+        tracker.record_failure("a", start);
+        tracker.record_success(&"a");
+        assert_eq!(None, tracker.record_failure("a", start));
+
+        tracker.record_failure("a", start);
+        let much_later = start + Duration::from_secs(60);
+        assert_eq!(None, tracker.record_failure("a", much_later));
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How the client is getting on with the server, as shown to the user
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Not yet heard from the server
+    Connecting,
+
+    Online,
+
+    /// Lost the server after having been online
+    Reconnecting,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::Connecting => write!(f, "◌ connecting"),
+            ConnectionState::Online => write!(f, "● online"),
+            ConnectionState::Reconnecting => write!(f, "◍ reconnecting"),
+        }
+    }
+}
+
+impl ConnectionState {
+    /// The state after failing to reach the server
+    pub fn failed(self) -> ConnectionState {
+        match self {
+            ConnectionState::Connecting => ConnectionState::Connecting,
+            _ => ConnectionState::Reconnecting,
+        }
+    }
+}
+
+/// Exponentially growing delays between attempts to reach the server, with "full jitter"
+/// so that many clients which lost the server at once don't all come back together
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        // Never retry immediately, however the dice fall
+        let floor = self.base.min(ceiling) / 2;
+        rand::thread_rng().gen_range(floor..=ceiling)
+    }
+
+    /// Start over from the shortest delay, after an attempt succeeded
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_within_bounds_and_resets() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+        let mut backoff = Backoff::new(base, max);
+
+        for ceiling in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay();
+            assert!(delay >= base / 2, "{:?} is too short", delay);
+            assert!(
+                delay <= Duration::from_millis(ceiling),
+                "{:?} exceeds {}ms",
+                delay,
+                ceiling
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= base);
+    }
+
+    #[test]
+    fn failing_before_coming_online_is_still_connecting() {
+        assert_eq!(
+            ConnectionState::Connecting,
+            ConnectionState::Connecting.failed()
+        );
+        assert_eq!(
+            ConnectionState::Reconnecting,
+            ConnectionState::Online.failed()
+        );
+    }
+}
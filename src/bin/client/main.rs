@@ -0,0 +1,1502 @@
+extern crate base64;
+extern crate clap;
+extern crate dotenv;
+extern crate hyper;
+extern crate rand;
+extern crate request_proxy;
+extern crate reqwest;
+extern crate serde;
+extern crate serde_json;
+extern crate tokio;
+extern crate uuid;
+
+use request_proxy::forwarded::{add_forwarding_headers, ForwardedFor, ForwardingStyle};
+use request_proxy::headers::{
+    apply_header_rules, strip_control_headers, strip_hop_by_hop, HeaderRule,
+};
+use request_proxy::protocol::{
+    agree, compress, decompress, negotiate, Capability, Endpoint, Hello, Versions, WireFormat,
+    CAPABILITIES, MAX_DECOMPRESSED_MESSAGE, PROTOCOL_VERSIONS, RESPONSE_TIMEOUT, VERSIONS_PATH,
+};
+use request_proxy::rewrite::{
+    is_text_content_type, origin, rewrite_body, rewrite_cookie_domain, rewrite_location,
+};
+use request_proxy::signal::shutdown_signal;
+use request_proxy::types::*;
+
+use clap::{Args, FromArgMatches, Parser, Subcommand, ValueEnum};
+use dotenv::dotenv;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::Version;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::redirect::Policy;
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+mod connection;
+mod retry;
+mod tcp;
+mod upstream;
+use connection::{Backoff, ConnectionState};
+use retry::{describe_upstream_error, send_with_retries, RetryPolicy, ATTEMPT_TIMEOUT};
+use tcp::{parse_tcp_target, TcpRelay};
+use upstream::{parse_target, route, HostHeader, Upstream};
+
+/// Expose a local service through a request-proxy server.
+///
+/// Every option may also be supplied through the environment (or a `.env` file).
+/// When no subcommand is given, the client behaves like `client http`.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// URL of the externally visible proxy server
+    #[arg(long, env = "PROXY_SERVER", global = true)]
+    server: Option<String>,
+
+    /// URL at which the server listens for clients, if that's apart from visitors;
+    /// defaults to --server
+    #[arg(long, env = "PROXY_CONTROL_SERVER", global = true)]
+    control_server: Option<String>,
+
+    /// Shared secret key used to authenticate with the server
+    #[arg(long, env = "PROXY_SECRET", global = true, hide_env_values = true)]
+    secret: Option<String>,
+
+    /// Directory in which forwarded requests are recorded for `replay`
+    #[arg(
+        long,
+        env = "PROXY_HISTORY_DIR",
+        default_value = ".request-proxy/history",
+        global = true
+    )]
+    history_dir: PathBuf,
+
+    /// Record forwarded requests in the history directory so they can be replayed
+    #[arg(long, env = "PROXY_HISTORY", global = true)]
+    history: bool,
+
+    /// Which headers tell the local service who originally sent a request
+    #[arg(
+        long,
+        value_enum,
+        env = "PROXY_FORWARDED_HEADERS",
+        default_value_t = ForwardedHeaders::Both,
+        global = true
+    )]
+    forwarded_headers: ForwardedHeaders,
+
+    /// Add to the forwarding headers visitors send instead of replacing them; only safe if
+    /// whatever is in front of the server always sets them itself
+    #[arg(long, env = "PROXY_TRUST_FORWARDED", global = true)]
+    trust_forwarded: bool,
+
+    /// Change the headers sent to the local service: `add NAME: VALUE`, `set NAME: VALUE`,
+    /// `remove NAME` or `rename NAME: NEW`. May be repeated; the environment variable
+    /// takes one rule per line.
+    #[arg(
+        long = "request-header",
+        env = "PROXY_REQUEST_HEADER_RULES",
+        value_name = "RULE",
+        value_delimiter = '\n',
+        global = true
+    )]
+    request_header_rules: Vec<HeaderRule>,
+
+    /// How many times to retry a request the local service couldn't answer. Requests which
+    /// never connected are always retried; others only if their method is idempotent.
+    #[arg(long, env = "PROXY_RETRIES", default_value_t = 2, global = true)]
+    retries: u32,
+
+    /// Milliseconds to wait before the first retry, doubling before each one after that
+    #[arg(long, env = "PROXY_RETRY_DELAY", default_value_t = 250, global = true)]
+    retry_delay: u64,
+
+    /// Longest wait, in seconds, between attempts to reach the server after losing it
+    #[arg(long, env = "PROXY_RECONNECT_MAX", default_value_t = 30, global = true)]
+    reconnect_max: u64,
+
+    /// Give up after the server rejects the secret this many times in a row
+    #[arg(
+        long,
+        env = "PROXY_MAX_AUTH_FAILURES",
+        default_value_t = 3,
+        global = true
+    )]
+    max_auth_failures: u32,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Forward HTTP requests received by the server to a local service
+    Http(HttpArgs),
+
+    /// Send a previously forwarded request to the local service again
+    Replay {
+        /// ID of the recorded request
+        id: Uuid,
+
+        /// Port, host:port, or URL of the service; defaults to $PROXY_HOST
+        #[arg(env = "PROXY_HOST")]
+        target: Option<String>,
+
+        /// How the `Host` header of the replayed request is set: rewrite, preserve, or a fixed value
+        #[arg(long, default_value = "rewrite")]
+        host_header: HostHeader,
+    },
+
+    /// Carry raw TCP connections made to the server's $TCP_PORT to a local service
+    Tcp {
+        /// Port or host:port of the service, eg: `5432` for a local database
+        target: String,
+    },
+
+    /// Check that the server and the local service are reachable
+    Status {
+        /// Port, host:port, or URL of the service; defaults to $PROXY_HOST
+        #[arg(env = "PROXY_HOST")]
+        target: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct HttpArgs {
+    /// Port, host:port, or URL of the service to expose; defaults to $PROXY_HOST
+    #[arg(env = "PROXY_HOST")]
+    target: Option<String>,
+
+    /// How the `Host` header of forwarded requests is set: rewrite, preserve, or a fixed value
+    #[arg(long, env = "PROXY_HOST_HEADER", default_value = "rewrite")]
+    host_header: HostHeader,
+
+    /// Point `Location` headers which refer to the local service at the server instead
+    #[arg(long, env = "PROXY_REWRITE_LOCATION")]
+    rewrite_location: bool,
+
+    /// Point cookies set for the local service's domain at the server instead
+    #[arg(long, env = "PROXY_REWRITE_COOKIES")]
+    rewrite_cookies: bool,
+
+    /// Replace links to the local service in HTML, JSON and other text bodies
+    #[arg(long, env = "PROXY_REWRITE_BODY")]
+    rewrite_body: bool,
+
+    /// Send requests under a path prefix to another service, eg: `/api=localhost:4000`.
+    ///
+    /// Takes `;`-separated options: `host=rewrite|preserve|VALUE`, `rewrite-location`,
+    /// `rewrite-cookies` and `rewrite-body`. The longest matching prefix wins.
+    #[arg(long = "route", env = "PROXY_ROUTES", value_delimiter = ',')]
+    routes: Vec<Upstream>,
+}
+
+impl HttpArgs {
+    /// Every service requests may be forwarded to; the target receives whatever isn't routed
+    fn upstreams(self) -> Vec<Upstream> {
+        let mut upstreams = self.routes;
+
+        if let Some(target) = self.target {
+            let destination = parse_target(&target).unwrap_or_else(|e| exit_with_error(&e));
+
+            upstreams.insert(
+                0,
+                Upstream {
+                    host_header: self.host_header,
+                    rewrite_location: self.rewrite_location,
+                    rewrite_cookies: self.rewrite_cookies,
+                    rewrite_body: self.rewrite_body,
+                    ..Upstream::new(destination)
+                },
+            );
+        }
+
+        upstreams
+    }
+}
+
+/// Controls which forwarding headers are sent to the local service
+#[derive(Clone, Copy, ValueEnum)]
+enum ForwardedHeaders {
+    /// Don't describe the original request
+    None,
+
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    XForwarded,
+
+    /// RFC 7239 `Forwarded`
+    Forwarded,
+
+    /// Both `X-Forwarded-*` and `Forwarded`
+    Both,
+}
+
+impl Cli {
+    fn forwarding_style(&self) -> ForwardingStyle {
+        ForwardingStyle {
+            x_forwarded: matches!(
+                self.forwarded_headers,
+                ForwardedHeaders::XForwarded | ForwardedHeaders::Both
+            ),
+            forwarded: matches!(
+                self.forwarded_headers,
+                ForwardedHeaders::Forwarded | ForwardedHeaders::Both
+            ),
+            trust_existing: self.trust_forwarded,
+        }
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries,
+            delay: Duration::from_millis(self.retry_delay),
+            // Leave time to get the response to the server before the visitor gives up
+            budget: RESPONSE_TIMEOUT.saturating_sub(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// Why the fuck doesn't the HTTP crate provide something like this already?
+fn version_from_str(ver: &str) -> Version {
+    match ver {
+        "HTTP/0.9" => Version::HTTP_09,
+        "HTTP/1.0" => Version::HTTP_10,
+        "HTTP/1.1" => Version::HTTP_11,
+        "HTTP/2.0" => Version::HTTP_2,
+        v => panic!("Received invalid HTTP version '{}'!", v),
+    }
+}
+
+/// Why the server couldn't be polled
+enum PollError {
+    /// The server couldn't be reached, or was in no state to answer
+    Unreachable,
+
+    /// The server rejected our secret
+    Unauthorized,
+}
+
+/// How to talk to the server, as agreed with it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    /// Every request goes to the server's root, told apart from visitors by the secret header
+    Legacy,
+
+    /// Requests go to the control endpoints of this version
+    Versioned(u32),
+}
+
+/// Everything needed to pull requests from the server and forward them upstream
+struct Tunnel {
+    /// Identifies this client to the server, so it can tell when we've gone away
+    id: Uuid,
+    client: Client,
+    server: String,
+
+    /// Where to send control requests; the same as `server` unless it has a separate listener
+    control: String,
+    secret: String,
+
+    /// Agreed with the server before the first poll, and again after losing it
+    protocol: Option<Protocol>,
+
+    /// What the server agreed to in its hello; nothing, for servers which predate it
+    capabilities: Vec<Capability>,
+    upstreams: Vec<Upstream>,
+    forwarding: ForwardingStyle,
+    header_rules: Vec<HeaderRule>,
+    retry: RetryPolicy,
+    history: Option<PathBuf>,
+
+    /// Set when carrying TCP connections, rather than HTTP requests
+    relay: Option<TcpRelay>,
+}
+
+impl Tunnel {
+    /// A tunnel to the server the command line names, which hasn't talked to it yet
+    fn new(cli: &Cli, upstreams: Vec<Upstream>, relay: Option<TcpRelay>) -> Tunnel {
+        // The hostname or IP of the server to which proxied requests were sent
+        let server = cli.server.clone().unwrap_or_else(|| {
+            exit_with_error("Missing proxy server! Pass --server or set $PROXY_SERVER.")
+        });
+
+        // Shared secret key for reading requests/pushing responses
+        let secret = cli.secret.clone().unwrap_or_else(|| {
+            exit_with_error("Missing secret key! Pass --secret or set $PROXY_SECRET.")
+        });
+
+        Tunnel {
+            id: Uuid::new_v4(),
+            client: build_client(),
+            control: cli.control_server.clone().unwrap_or_else(|| server.clone()),
+            server,
+            secret,
+            protocol: None,
+            capabilities: Vec::new(),
+            upstreams,
+            forwarding: cli.forwarding_style(),
+            header_rules: cli.request_header_rules.clone(),
+            retry: cli.retry_policy(),
+            history: cli.history.then(|| cli.history_dir.clone()),
+            relay,
+        }
+    }
+
+    /// Start building an authenticated request to one of the server's endpoints
+    fn server_request(&self, endpoint: Endpoint) -> RequestBuilder {
+        let (method, url) = match self.protocol {
+            Some(Protocol::Versioned(version)) => (
+                endpoint.method(),
+                format!(
+                    "{}{}",
+                    self.control.trim_end_matches('/'),
+                    endpoint.path(version)
+                ),
+            ),
+            _ => (
+                endpoint
+                    .legacy_method()
+                    .expect("Older servers only poll, respond and disconnect"),
+                self.control.clone(),
+            ),
+        };
+
+        self.client
+            .request(method, url)
+            .header("x-proxy-secret", self.secret.as_str())
+            .header("x-proxy-client-id", self.id.hyphenated().to_string())
+    }
+
+    /// Find out which protocol the server speaks
+    async fn negotiate(&self) -> Result<Protocol, PollError> {
+        let url = format!("{}{}", self.control.trim_end_matches('/'), VERSIONS_PATH);
+
+        let response = match self
+            .client
+            .request(Method::OPTIONS, &url)
+            .header("x-proxy-secret", self.secret.as_str())
+            .send()
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        match response.status() {
+            StatusCode::OK => {}
+            // Servers predating the control endpoints only know GET, POST and DELETE
+            StatusCode::METHOD_NOT_ALLOWED => return Ok(Protocol::Legacy),
+            StatusCode::UNAUTHORIZED => {
+                println!("Error: Unauthorized! Is the $PROXY_SECRET correct?");
+                return Err(PollError::Unauthorized);
+            }
+            StatusCode::NOT_FOUND => exit_with_error(&format!(
+                "{} doesn't serve clients; if the server listens for them on a separate port, \
+                 pass --control-server or set $PROXY_CONTROL_SERVER.",
+                self.control
+            )),
+            status => {
+                eprintln!("ERROR: Server responded {} to {}", status, VERSIONS_PATH);
+                return Err(PollError::Unreachable);
+            }
+        }
+
+        let theirs = match response.json::<Versions>().await {
+            Ok(versions) => versions.versions,
+            Err(e) => {
+                eprintln!(
+                    "Failed to read the server's protocol versions! Error: {}",
+                    e
+                );
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        match negotiate(&PROTOCOL_VERSIONS, &theirs) {
+            Some(version) => Ok(Protocol::Versioned(version)),
+            None => exit_with_error(&format!(
+                "The server speaks protocol versions {:?}, but this client only speaks {:?}; \
+                 upgrade whichever is older.",
+                theirs, PROTOCOL_VERSIONS
+            )),
+        }
+    }
+
+    /// Tell the server what we support, returning what it agreed to
+    async fn hello(&self, version: u32) -> Result<Vec<Capability>, PollError> {
+        let hello = Hello {
+            version,
+            capabilities: CAPABILITIES.to_vec(),
+        };
+
+        let response = match self
+            .server_request(Endpoint::Hello)
+            .json(&hello)
+            .send()
+            .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        match response.status() {
+            StatusCode::OK => {}
+            // The server has nothing to offer beyond the protocol version itself
+            StatusCode::NOT_FOUND => return Ok(Vec::new()),
+            StatusCode::UNAUTHORIZED => {
+                println!("Error: Unauthorized! Is the $PROXY_SECRET correct?");
+                return Err(PollError::Unauthorized);
+            }
+            StatusCode::UPGRADE_REQUIRED => exit_with_error(&format!(
+                "The server refused this client: {}",
+                response.text().await.unwrap_or_default()
+            )),
+            status => {
+                eprintln!("ERROR: Server responded {} to hello", status);
+                return Err(PollError::Unreachable);
+            }
+        }
+
+        match response.json::<Hello>().await {
+            Ok(answer) => Ok(agree(&CAPABILITIES, &answer.capabilities)),
+            Err(e) => {
+                eprintln!("Failed to read the server's hello! Error: {}", e);
+                Err(PollError::Unreachable)
+            }
+        }
+    }
+
+    /// Agree on a protocol version and capabilities with the server
+    async fn connect(&mut self) -> Result<(), PollError> {
+        let protocol = self.negotiate().await?;
+        self.protocol = Some(protocol);
+
+        self.capabilities = match protocol {
+            Protocol::Versioned(version) => match self.hello(version).await {
+                Ok(capabilities) => capabilities,
+                Err(e) => {
+                    self.protocol = None;
+                    return Err(e);
+                }
+            },
+            Protocol::Legacy => Vec::new(),
+        };
+
+        match protocol {
+            Protocol::Versioned(version) => println!(
+                "Using protocol version {} with capabilities [{}]",
+                version,
+                self.capabilities
+                    .iter()
+                    .map(|capability| capability.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Protocol::Legacy => println!("The server predates versioned control endpoints"),
+        }
+
+        Ok(())
+    }
+
+    /// Fetch and forward one request, if there is one.
+    ///
+    /// Errors mean the server couldn't be polled at all; everything else is handled here.
+    async fn poll(&mut self) -> Result<(), PollError> {
+        if self.protocol.is_none() {
+            self.connect().await?;
+        }
+
+        if let Some(relay) = &self.relay {
+            let result = self.poll_streams(relay).await;
+
+            if result.is_err() {
+                // Whatever the server was carrying is lost with it
+                relay.close_all();
+                self.protocol = None;
+            }
+
+            return result;
+        }
+
+        // Send poll for any new requests.
+        let request = self.server_request(Endpoint::Poll).send();
+
+        let response = match request.await {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+
+                // It may come back as a different version of itself
+                self.protocol = None;
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        let response_status = response.status();
+        let format = WireFormat::from_content_type(
+            response
+                .headers()
+                .get("content-type")
+                .and_then(|h| h.to_str().ok()),
+        );
+
+        // Read the server's response.
+        let content = read_message(response).await?;
+
+        match response_status {
+            // If the server just responded No Content then there's no requests at the moment.
+            StatusCode::NO_CONTENT => {
+                sleep(Duration::from_millis(500)).await;
+                return Ok(());
+            }
+            // If the server responses unauthorized, then the secret key is probably wrong.
+            StatusCode::UNAUTHORIZED => {
+                println!("Error: Unauthorized! Is the $PROXY_SECRET correct?");
+                println!("Server responded: {}", String::from_utf8_lossy(&content));
+                return Err(PollError::Unauthorized);
+            }
+            // The server wants something we didn't agree to, perhaps having restarted since
+            StatusCode::UPGRADE_REQUIRED => {
+                let reason = String::from_utf8_lossy(&content);
+
+                if self.protocol == Some(Protocol::Legacy) {
+                    exit_with_error(&format!("The server refused this client: {}", reason));
+                }
+
+                eprintln!("ERROR: {}", reason);
+                self.protocol = None;
+                return Err(PollError::Unreachable);
+            }
+            // The server (or something in front of it) is struggling, or has locked us out
+            status if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS => {
+                eprintln!("ERROR: Server responded {}", status);
+                return Err(PollError::Unreachable);
+            }
+            // Everything else should be fine.
+            _ => {}
+        };
+
+        // Try to decode the request
+        let request: ProxiedRequest = match format.decode(&content) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                return Ok(());
+            }
+        };
+
+        if let Some(dir) = &self.history {
+            // Recorded requests are always JSON, however they arrived
+            let recorded = match format {
+                WireFormat::Json => String::from_utf8_lossy(&content).into_owned(),
+                _ => serde_json::to_string(&request).expect("Failed to serialize to JSON"),
+            };
+
+            if let Err(e) = record_request(dir, request.id, &recorded) {
+                eprintln!("WARNING: Failed to record request {}: {}", request.id, e);
+            }
+        }
+
+        let upstream = match route(&self.upstreams, &request.uri.path) {
+            Some(upstream) => upstream,
+            None => {
+                println!("No upstream is configured for {}", request.uri.path);
+
+                let not_routed = ClientResponse {
+                    request_id: request.id,
+                    status: 502,
+                    headers: vec![(
+                        String::from("content-type"),
+                        Base64Bytes(b"text/plain; charset=utf-8".to_vec()),
+                    )],
+                    body: Base64Bytes("🧭 No service is configured for this path".into()),
+                };
+
+                self.send_response(&not_routed, "error notification").await;
+                return Ok(());
+            }
+        };
+
+        let public = public_url(&request, &self.server);
+
+        let (proxied_response, description) = match forward(
+            &self.client,
+            &request,
+            upstream,
+            public.as_ref(),
+            self.forwarding,
+            &self.header_rules,
+            self.retry,
+        )
+        .await
+        {
+            Ok(r) => (r, "response"),
+            Err(e) => {
+                let reason = describe_upstream_error(&e);
+                println!(
+                    "Failed to reach {}: {} ({:?})",
+                    upstream.destination, reason, e
+                );
+
+                (
+                    upstream_error_response(request.id, reason),
+                    "error notification",
+                )
+            }
+        };
+
+        self.send_response(&proxied_response, description).await;
+        Ok(())
+    }
+
+    /// Carry TCP connections until the server has nothing more to say for now
+    async fn poll_streams(&self, relay: &TcpRelay) -> Result<(), PollError> {
+        if self.protocol == Some(Protocol::Legacy) {
+            exit_with_error("The server predates TCP connections; upgrade it to use `client tcp`.");
+        }
+
+        let receiving = self.receive_stream_events(relay);
+        tokio::pin!(receiving);
+
+        // Whatever the local service sends goes out while we're waiting to hear from the server
+        loop {
+            tokio::select! {
+                result = &mut receiving => return result,
+                events = relay.outgoing() => self.send_stream_events(events).await?,
+            }
+        }
+    }
+
+    /// Wait for the server to say what happened to visitors' connections, and act on it
+    async fn receive_stream_events(&self, relay: &TcpRelay) -> Result<(), PollError> {
+        let response = match self.server_request(Endpoint::StreamPoll).send().await {
+            Ok(res) => res,
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        match response.status() {
+            StatusCode::OK => {}
+            // Nothing happened while the server kept us waiting
+            StatusCode::NO_CONTENT => return Ok(()),
+            StatusCode::UNAUTHORIZED => {
+                println!("Error: Unauthorized! Is the $PROXY_SECRET correct?");
+                return Err(PollError::Unauthorized);
+            }
+            StatusCode::NOT_FOUND => {
+                exit_with_error("The server doesn't accept TCP connections; set $TCP_PORT on it.")
+            }
+            status => {
+                eprintln!("ERROR: Server responded {}", status);
+                return Err(PollError::Unreachable);
+            }
+        }
+
+        let content = read_message(response).await?;
+        let events: Vec<StreamEvent> = match serde_json::from_slice(&content) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Failed to read stream events! Error: {}", e);
+                return Err(PollError::Unreachable);
+            }
+        };
+
+        for event in events {
+            relay.handle(event);
+        }
+
+        Ok(())
+    }
+
+    /// Tell the server what happened to the local ends of visitors' connections
+    async fn send_stream_events(&self, events: Vec<StreamEvent>) -> Result<(), PollError> {
+        let message = serde_json::to_vec(&events).expect("Failed to serialize to JSON");
+        let (body, encoding) = if self.capabilities.contains(&Capability::Compression) {
+            compress(message)
+        } else {
+            (message, None)
+        };
+
+        let mut request = self
+            .server_request(Endpoint::StreamSend)
+            .header("content-type", WireFormat::Json.content_type())
+            .body(body);
+        if let Some(encoding) = encoding {
+            request = request.header("content-encoding", encoding);
+        }
+
+        match request.send().await {
+            Ok(r) if r.status().is_success() => Ok(()),
+            Ok(r) if r.status() == StatusCode::UNAUTHORIZED => {
+                println!("Error: Unauthorized! Is the $PROXY_SECRET correct?");
+                Err(PollError::Unauthorized)
+            }
+            Ok(r) => {
+                eprintln!("ERROR: Server rejected stream events ({})", r.status());
+                Err(PollError::Unreachable)
+            }
+            Err(e) => {
+                eprintln!("ERROR: {}", e);
+                Err(PollError::Unreachable)
+            }
+        }
+    }
+
+    async fn send_response(&self, response: &ClientResponse, description: &str) {
+        let format = WireFormat::agreed(&self.capabilities);
+        let message = format.encode(response);
+        let (body, encoding) = if self.capabilities.contains(&Capability::Compression) {
+            compress(message)
+        } else {
+            (message, None)
+        };
+
+        let mut request = self
+            .server_request(Endpoint::Respond)
+            .header("content-type", format.content_type())
+            .body(body);
+        if let Some(encoding) = encoding {
+            request = request.header("content-encoding", encoding);
+        }
+
+        match request.send().await {
+            Ok(r) if r.status().is_success() => {
+                println!(
+                    "\n=====================\nSuccessfully sent {} to the server",
+                    description
+                );
+            }
+            // Eg: the visitor stopped waiting, or the request was already answered
+            Ok(r) => {
+                let status = r.status();
+                println!(
+                    "ERROR: Server rejected {} ({}): {}",
+                    description,
+                    status,
+                    r.text().await.unwrap_or_default()
+                );
+            }
+            Err(e) => {
+                println!("ERROR: Failed to send {} to server! {:?}", description, e);
+            }
+        };
+
+        println!("\n-------------------------------------------\n");
+    }
+
+    /// Tell the server we're going away, so it doesn't wait on us for queued requests
+    async fn disconnect(&self) {
+        // We never got as far as talking to the server
+        if self.protocol.is_none() {
+            return;
+        }
+
+        match self.server_request(Endpoint::Disconnect).send().await {
+            Ok(r) if r.status().is_success() => println!("Disconnected from the server"),
+            Ok(r) => eprintln!("ERROR: Server refused disconnect: {}", r.status()),
+            Err(e) => eprintln!("ERROR: Failed to notify server of disconnect! {:?}", e),
+        }
+    }
+}
+
+/// Read a control message from the server, undoing any compression
+async fn read_message(response: reqwest::Response) -> Result<Vec<u8>, PollError> {
+    let encoding = response
+        .headers()
+        .get("content-encoding")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string());
+
+    match response.bytes().await.map(|bytes| {
+        decompress(
+            bytes.to_vec(),
+            encoding.as_deref(),
+            MAX_DECOMPRESSED_MESSAGE,
+        )
+    }) {
+        Ok(Ok(c)) => Ok(c),
+        Ok(Err(e)) => {
+            eprintln!("Failed to decompress response body! Error: {}", e);
+            Err(PollError::Unreachable)
+        }
+        Err(e) => {
+            eprintln!("Failed to read response body! Error: {}", e);
+            Err(PollError::Unreachable)
+        }
+    }
+}
+
+/// Sends the proxied request to the destination, printing both the request and the response
+async fn forward(
+    client: &Client,
+    request: &ProxiedRequest<'_>,
+    upstream: &Upstream,
+    public: Option<&Url>,
+    forwarding: ForwardingStyle,
+    header_rules: &[HeaderRule],
+    retry: RetryPolicy,
+) -> Result<ClientResponse, reqwest::Error> {
+    let method = Method::from_str(request.method).unwrap();
+
+    let mut url = upstream.destination.clone();
+    url.set_path(&request.uri.path);
+    url.set_query(request.uri.query.as_deref());
+    url.set_fragment(request.uri.fragment.as_deref());
+
+    let mut headers = build_headers(request);
+    let body = request.body.0.clone();
+
+    if let Some(host) = upstream.host() {
+        let _ = headers.insert("host", HeaderValue::from_str(&host).unwrap());
+    }
+
+    // Only requests from servers which record where they came from can be described
+    if let Some(addr) = request.remote_addr {
+        let original = ForwardedFor {
+            addr,
+            proto: request.scheme.as_deref().unwrap_or("http"),
+            host: request.host.as_deref(),
+        };
+
+        add_forwarding_headers(&mut headers, &original, forwarding);
+    }
+
+    apply_header_rules(header_rules, &mut headers);
+
+    let mut full_url = url.path().to_string();
+    if let Some(query) = url.query() {
+        full_url.push('?');
+        full_url.push_str(query);
+    }
+    if let Some(fragment) = url.fragment() {
+        full_url.push('#');
+        full_url.push_str(fragment);
+    }
+
+    // Print the first line; eg: "GET /some/resource HTTP/1.1".
+    println!(
+        "{} {} {:?}",
+        &method,
+        &full_url,
+        version_from_str(&request.version)
+    );
+
+    // Print all of the headers
+    print_headers(&headers);
+
+    // Print the request body
+    println!("\n\n{}", String::from_utf8_lossy(&body));
+
+    println!("\n");
+
+    let r = send_with_retries(client, &method, &url, &headers, &body, retry).await?;
+    let r_status = r.status();
+    let mut r_headers = r.headers().clone();
+
+    let mut body = r.bytes().await?.to_vec();
+
+    println!("{}", r_status);
+
+    // Print all of the headers
+    print_headers(&r_headers);
+
+    println!("\n\n{}", String::from_utf8_lossy(&body));
+
+    strip_hop_by_hop(&mut r_headers);
+    strip_control_headers(&mut r_headers);
+
+    // The server sizes the body it sends to the visitor itself. A HEAD response has no body,
+    // though, so there the length of the body the visitor would've received is kept.
+    if method != Method::HEAD {
+        r_headers.remove("content-length");
+    }
+
+    if let Some(public) = public {
+        rewrite_response_headers(&mut r_headers, upstream, public);
+
+        if upstream.rewrite_body {
+            if let Some(rewritten) = rewrite_response_body(&r_headers, &body, upstream, public) {
+                body = rewritten;
+
+                // The tag described the body the local service sent, which nobody will see
+                r_headers.remove("etag");
+            }
+        }
+    }
+
+    // Build the response to send back to the server
+    Ok(ClientResponse {
+        request_id: request.id,
+        status: r_status.as_u16(),
+        headers: ClientResponse::parse_header_map(&r_headers),
+        body: Base64Bytes(body),
+    })
+}
+
+/// Tell the visitor why there's no response, rather than leaving them guessing, but keep the
+/// address of the local service to ourselves
+fn upstream_error_response(request_id: Uuid, reason: &str) -> ClientResponse {
+    ClientResponse {
+        request_id,
+        status: 502,
+        headers: vec![(
+            String::from("content-type"),
+            Base64Bytes(b"text/plain; charset=utf-8".to_vec()),
+        )],
+        body: Base64Bytes(
+            format!(
+                "🔌 The service behind this proxy couldn't be reached: {}",
+                reason
+            )
+            .into_bytes(),
+        ),
+    }
+}
+
+/// Point redirects and cookies meant for the local service at the URL visitors use instead
+fn rewrite_response_headers(headers: &mut HeaderMap, upstream: &Upstream, public: &Url) {
+    if upstream.rewrite_location {
+        let rewritten = headers
+            .get("location")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|location| rewrite_location(location, &upstream.destination, public))
+            .and_then(|location| HeaderValue::from_str(&location).ok());
+
+        if let Some(location) = rewritten {
+            headers.insert("location", location);
+        }
+    }
+
+    if let (true, Some(from), Some(to)) = (
+        upstream.rewrite_cookies,
+        upstream.destination.host_str(),
+        public.host_str(),
+    ) {
+        let cookies: Vec<HeaderValue> = headers
+            .get_all("set-cookie")
+            .iter()
+            .map(|cookie| {
+                cookie
+                    .to_str()
+                    .ok()
+                    .and_then(|c| rewrite_cookie_domain(c, from, to))
+                    .and_then(|c| HeaderValue::from_str(&c).ok())
+                    .unwrap_or_else(|| cookie.clone())
+            })
+            .collect();
+
+        headers.remove("set-cookie");
+        for cookie in cookies {
+            headers.append("set-cookie", cookie);
+        }
+    }
+}
+
+/// Replace links to the local service in a text body with links to the URL visitors use
+fn rewrite_response_body(
+    headers: &HeaderMap,
+    body: &[u8],
+    upstream: &Upstream,
+    public: &Url,
+) -> Option<Vec<u8>> {
+    let content_type = headers.get("content-type")?.to_str().ok()?;
+
+    if !is_text_content_type(content_type) {
+        return None;
+    }
+
+    let content_encoding = match headers.get("content-encoding") {
+        Some(encoding) => Some(encoding.to_str().ok()?),
+        None => None,
+    };
+
+    rewrite_body(
+        body,
+        content_encoding,
+        &origin(&upstream.destination),
+        &origin(public),
+    )
+}
+
+/// The URL visitors used to reach the server, or the server's own URL if it didn't say
+fn public_url(request: &ProxiedRequest, server: &str) -> Option<Url> {
+    match (&request.scheme, &request.host) {
+        (Some(scheme), Some(host)) => Url::parse(&format!("{}://{}/", scheme, host)).ok(),
+        _ => Url::parse(server).ok(),
+    }
+}
+
+fn print_headers(headers: &HeaderMap) {
+    for (key, value) in headers.iter() {
+        let value_display = value.to_str().unwrap_or("[undisplayable value]");
+        println!("{}: {}", key, value_display);
+    }
+}
+
+/// Saves the raw JSON of a proxied request so it can be replayed later
+fn record_request(dir: &Path, id: Uuid, content: &str) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(format!("{}.json", id.hyphenated())), content)
+}
+
+fn build_client() -> Client {
+    Client::builder()
+        .redirect(Policy::none())
+        .timeout(ATTEMPT_TIMEOUT)
+        .connect_timeout(Duration::from_secs(5))
+        .build()
+        .unwrap()
+}
+
+/// Print the message and exit with a failure status
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(2);
+}
+
+/// Reads the upstream from the command line or $PROXY_HOST
+fn require_target(target: Option<String>) -> Url {
+    let target = target.unwrap_or_else(|| {
+        exit_with_error("Missing destination! Pass a port, host:port, or URL, or set $PROXY_HOST.")
+    });
+
+    parse_target(&target).unwrap_or_else(|e| exit_with_error(&e))
+}
+
+async fn run_http(cli: Cli, args: HttpArgs) {
+    let mut tunnel = Tunnel::new(&cli, Vec::new(), None);
+
+    // Services to which to send proxied requests
+    tunnel.upstreams = args.upstreams();
+
+    if tunnel.upstreams.is_empty() {
+        exit_with_error("Missing destination! Pass a port, host:port, or URL, or set $PROXY_HOST.");
+    }
+
+    for upstream in &tunnel.upstreams {
+        println!(
+            "Forwarding {}{} to {}",
+            tunnel.server.trim_end_matches('/'),
+            upstream.prefix,
+            upstream.destination
+        );
+    }
+
+    run_tunnel(cli, tunnel).await
+}
+
+async fn run_tcp(cli: Cli, target: String) {
+    let target = parse_tcp_target(&target).unwrap_or_else(|e| exit_with_error(&e));
+    let tunnel = Tunnel::new(&cli, Vec::new(), Some(TcpRelay::new(target.clone())));
+
+    println!(
+        "Forwarding TCP connections to {} to {}",
+        tunnel.server.trim_end_matches('/'),
+        target
+    );
+
+    run_tunnel(cli, tunnel).await
+}
+
+/// Keep the tunnel going, reconnecting whenever the server is lost, until we're told to stop
+async fn run_tunnel(cli: Cli, mut tunnel: Tunnel) {
+    let max_auth_failures = cli.max_auth_failures.max(1);
+    let mut backoff = Backoff::new(
+        Duration::from_millis(500),
+        Duration::from_secs(cli.reconnect_max.max(1)),
+    );
+
+    let stopping = Arc::new(AtomicBool::new(false));
+
+    // Cuts short the wait before trying a lost server again
+    let stopped = Arc::new(Notify::new());
+
+    // The first signal lets the current request finish; a second one quits immediately.
+    let stop_flag = stopping.clone();
+    let stop_notice = stopped.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        println!("Shutting down after the current request; press Ctrl-C again to quit now.");
+        stop_flag.store(true, Ordering::SeqCst);
+        stop_notice.notify_one();
+
+        shutdown_signal().await;
+        process::exit(130);
+    });
+
+    tokio::spawn(async move {
+        let mut state = ConnectionState::Connecting;
+        let mut auth_failures = 0;
+        println!("Server {}", state);
+
+        while !stopping.load(Ordering::SeqCst) {
+            let result = tunnel.poll().await;
+
+            let next_state = match result {
+                Ok(()) => ConnectionState::Online,
+                Err(_) => state.failed(),
+            };
+            if next_state != state {
+                println!("Server {}", next_state);
+                state = next_state;
+            }
+
+            match result {
+                Ok(()) => {
+                    backoff.reset();
+                    auth_failures = 0;
+                    continue;
+                }
+                Err(PollError::Unauthorized) => {
+                    auth_failures += 1;
+
+                    if auth_failures >= max_auth_failures {
+                        exit_with_error(&format!(
+                            "The server rejected $PROXY_SECRET {} times in a row; giving up.",
+                            auth_failures
+                        ));
+                    }
+                }
+                Err(PollError::Unreachable) => {}
+            }
+
+            let delay = backoff.next_delay();
+            println!("Trying the server again in {:.1}s", delay.as_secs_f32());
+
+            tokio::select! {
+                _ = sleep(delay) => {}
+                _ = stopped.notified() => break,
+            }
+        }
+
+        tunnel.disconnect().await;
+    })
+    .await
+    .unwrap()
+}
+
+async fn run_replay(cli: Cli, id: Uuid, target: Option<String>, host_header: HostHeader) {
+    let upstream = Upstream {
+        host_header,
+        ..Upstream::new(require_target(target))
+    };
+
+    let recorded_any = fs::read_dir(&cli.history_dir)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if !recorded_any {
+        exit_with_error(&format!(
+            "No requests have been recorded in '{}'; start the client with --history \
+             (or set $PROXY_HISTORY=true) to record forwarded requests for replay",
+            cli.history_dir.display()
+        ));
+    }
+
+    let path = cli.history_dir.join(format!("{}.json", id.hyphenated()));
+    let content = fs::read_to_string(&path).unwrap_or_else(|e| {
+        exit_with_error(&format!(
+            "Failed to read recorded request '{}': {}",
+            path.display(),
+            e
+        ))
+    });
+
+    let request: ProxiedRequest = serde_json::from_str(&content)
+        .unwrap_or_else(|e| exit_with_error(&format!("Recorded request is invalid: {}", e)));
+
+    let forwarding = cli.forwarding_style();
+
+    if let Err(e) = forward(
+        &build_client(),
+        &request,
+        &upstream,
+        None,
+        forwarding,
+        &cli.request_header_rules,
+        cli.retry_policy(),
+    )
+    .await
+    {
+        exit_with_error(&format!("Request failed: {}", e));
+    }
+}
+
+async fn run_status(cli: Cli, target: Option<String>) {
+    let client = build_client();
+    let mut healthy = true;
+
+    match (&cli.server, &cli.secret) {
+        (Some(server), Some(secret)) => {
+            let control = cli.control_server.as_deref().unwrap_or(server);
+
+            // Asking for the protocol versions shows whether the secret is right, without
+            // taking a request off the queue even on servers which predate the question.
+            match client
+                .request(
+                    Method::OPTIONS,
+                    format!("{}{}", control.trim_end_matches('/'), VERSIONS_PATH),
+                )
+                .header("x-proxy-secret", secret.as_str())
+                .send()
+                .await
+            {
+                Ok(r) if r.status() == StatusCode::UNAUTHORIZED => {
+                    healthy = false;
+                    println!(
+                        "Server   {}: reachable, but $PROXY_SECRET was rejected",
+                        control
+                    );
+                }
+                Ok(r) if r.status() == StatusCode::NOT_FOUND => {
+                    healthy = false;
+                    println!(
+                        "Server   {}: reachable, but doesn't serve clients \
+                         (set --control-server or $PROXY_CONTROL_SERVER)",
+                        control
+                    );
+                }
+                Ok(r) if r.status() == StatusCode::METHOD_NOT_ALLOWED => {
+                    println!("Server   {}: online (legacy protocol)", control)
+                }
+                Ok(_) => println!("Server   {}: online", control),
+                Err(e) => {
+                    healthy = false;
+                    println!("Server   {}: unreachable ({})", control, e);
+                }
+            }
+        }
+        (None, _) => {
+            healthy = false;
+            println!("Server   not configured (set --server or $PROXY_SERVER)");
+        }
+        (Some(server), None) => {
+            healthy = false;
+            println!(
+                "Server   {}: no secret configured (set --secret or $PROXY_SECRET)",
+                server
+            );
+        }
+    }
+
+    match target.as_deref().map(parse_target) {
+        Some(Ok(destination)) => match client.head(destination.clone()).send().await {
+            Ok(r) => println!("Upstream {}: online ({})", destination, r.status()),
+            Err(e) => {
+                healthy = false;
+                println!("Upstream {}: unreachable ({})", destination, e);
+            }
+        },
+        Some(Err(e)) => {
+            healthy = false;
+            println!("Upstream {}", e);
+        }
+        None => println!("Upstream not configured (pass a target or set $PROXY_HOST)"),
+    }
+
+    if !healthy {
+        process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv().ok();
+
+    let mut cli = Cli::parse();
+
+    match cli.command.take() {
+        Some(Command::Http(args)) => run_http(cli, args).await,
+        Some(Command::Replay {
+            id,
+            target,
+            host_header,
+        }) => run_replay(cli, id, target, host_header).await,
+        Some(Command::Tcp { target }) => run_tcp(cli, target).await,
+        Some(Command::Status { target }) => run_status(cli, target).await,
+        None => {
+            // Behave just like `client http`, taking every option from the environment
+            let matches = HttpArgs::augment_args(clap::Command::new("http"))
+                .try_get_matches_from(["http"])
+                .and_then(|matches| HttpArgs::from_arg_matches(&matches))
+                .unwrap_or_else(|e| e.exit());
+
+            run_http(cli, matches).await
+        }
+    }
+}
+
+/// Builds a Headers object from the raw header values in the ProxiedRequest,
+/// leaving out those which only concern the tunnel
+fn build_headers(request: &ProxiedRequest) -> HeaderMap {
+    let mut headers = request
+        .headers
+        .iter()
+        .fold(HeaderMap::new(), |mut headers, &(k, ref v)| {
+            let value_bytes: &[u8] = v.0.as_ref();
+            headers.append(
+                HeaderName::from_str(k).unwrap(),
+                HeaderValue::from_bytes(value_bytes).unwrap(),
+            );
+            headers
+        });
+
+    strip_hop_by_hop(&mut headers);
+    strip_control_headers(&mut headers);
+
+    // Recomputed by reqwest from the body which is actually sent
+    headers.remove("content-length");
+
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Cli {
+        Cli::try_parse_from(args).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    #[test]
+    fn parses_subcommands_and_their_flags() {
+        let cli = parse(&[
+            "client",
+            "http",
+            "3000",
+            "--host-header",
+            "preserve",
+            "--route",
+            "/api=localhost:4000;rewrite-body",
+            "--rewrite-cookies",
+            "--server",
+            "https://proxy.example.test",
+            "--retries",
+            "5",
+        ]);
+        let Some(Command::Http(args)) = cli.command else {
+            panic!("Expected the http subcommand");
+        };
+        assert_eq!(Some("3000"), args.target.as_deref());
+        assert_eq!(HostHeader::Preserve, args.host_header);
+        assert!(args.rewrite_cookies);
+        assert_eq!("/api", args.routes[0].prefix);
+        assert!(args.routes[0].rewrite_body);
+        assert_eq!(Some("https://proxy.example.test"), cli.server.as_deref());
+        assert_eq!(5, cli.retries);
+
+        let id = Uuid::new_v4();
+        let cli = parse(&[
+            "client",
+            "--history",
+            "replay",
+            &id.to_string(),
+            "localhost:8080",
+            "--host-header",
+            "app.example.test",
+        ]);
+        assert!(cli.history);
+        let Some(Command::Replay {
+            id: replayed,
+            target,
+            host_header,
+        }) = cli.command
+        else {
+            panic!("Expected the replay subcommand");
+        };
+        assert_eq!(id, replayed);
+        assert_eq!(Some("localhost:8080"), target.as_deref());
+        assert_eq!(HostHeader::Fixed("app.example.test".into()), host_header);
+
+        let cli = parse(&["client", "tcp", "5432", "--secret", "hunter2"]);
+        assert!(matches!(cli.command, Some(Command::Tcp { target }) if target == "5432"));
+        assert_eq!(Some("hunter2"), cli.secret.as_deref());
+
+        let cli = parse(&["client", "status", "localhost:3000"]);
+        assert!(matches!(
+            cli.command,
+            Some(Command::Status { target: Some(target) }) if target == "localhost:3000"
+        ));
+    }
+
+    #[tokio::test]
+    async fn tells_visitors_why_the_service_was_unreachable_but_not_where_it_is() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let destination =
+            Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        let request = ProxiedRequest {
+            method: "GET",
+            uri: RequestUri::from_str("/").unwrap(),
+            version: String::from("HTTP/1.1"),
+            headers: vec![],
+            body: Base64Bytes(vec![]),
+            id: Uuid::new_v4(),
+            remote_addr: None,
+            scheme: None,
+            host: None,
+        };
+
+        let Err(error) = forward(
+            &build_client(),
+            &request,
+            &Upstream::new(destination.clone()),
+            None,
+            ForwardingStyle {
+                x_forwarded: false,
+                forwarded: false,
+                trust_existing: false,
+            },
+            &[],
+            RetryPolicy {
+                retries: 0,
+                delay: Duration::ZERO,
+                budget: Duration::from_secs(10),
+            },
+        )
+        .await
+        else {
+            panic!("Nothing should be listening at {}", destination);
+        };
+
+        let response = upstream_error_response(request.id, describe_upstream_error(&error));
+        let body = response.body.as_str().unwrap();
+        assert_eq!(502, response.status);
+        assert_eq!(
+            "🔌 The service behind this proxy couldn't be reached: connection refused",
+            body
+        );
+        assert!(!body.contains(destination.host_str().unwrap()));
+    }
+
+    #[test]
+    fn runs_http_without_a_subcommand() {
+        let cli = parse(&["client", "--server", "https://proxy.example.test"]);
+        assert!(cli.command.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(Cli::try_parse_from(["client", "tcp"]).is_err());
+        assert!(Cli::try_parse_from(["client", "replay", "not-an-id"]).is_err());
+        assert!(Cli::try_parse_from(["client", "http", "--forwarded-headers", "some"]).is_err());
+        assert!(Cli::try_parse_from(["client", "http", "--route", "no-prefix"]).is_err());
+    }
+}
@@ -0,0 +1,292 @@
+use std::error::Error;
+use std::io;
+use std::time::{Duration, Instant};
+
+use request_proxy::dispatch::is_idempotent;
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, Response, Url};
+use tokio::time::sleep;
+
+/// Longest wait between two attempts, however many retries are allowed
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest a single attempt at reaching the local service may take
+pub const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How hard to try reaching the local service before giving up on a request
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Attempts made after the first one fails
+    pub retries: u32,
+
+    /// Wait before the first retry; doubled before each one after that, up to `MAX_RETRY_DELAY`
+    pub delay: Duration,
+
+    /// Most time to spend on a request, every attempt and wait included; there's no point
+    /// answering after the visitor has stopped waiting
+    pub budget: Duration,
+}
+
+/// Whether a failed request may be sent again.
+///
+/// A request which couldn't connect never reached the service, so it's always safe to retry.
+/// Otherwise, only idempotent requests are, since the service may have acted on the first one.
+fn is_retryable(method: &Method, error: &reqwest::Error) -> bool {
+    error.is_connect() || (is_idempotent(method) && (error.is_timeout() || error.is_request()))
+}
+
+/// Send a request to the local service, retrying with backoff when that's safe
+pub async fn send_with_retries(
+    client: &Client,
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    body: &[u8],
+    policy: RetryPolicy,
+) -> Result<Response, reqwest::Error> {
+    let started = Instant::now();
+    let mut delay = policy.delay;
+    let mut attempt = 0;
+
+    loop {
+        let remaining = policy.budget.saturating_sub(started.elapsed());
+
+        let result = client
+            .request(method.clone(), url.clone())
+            .headers(headers.clone())
+            .body(body.to_vec())
+            .timeout(remaining.min(ATTEMPT_TIMEOUT))
+            .send()
+            .await;
+
+        match result {
+            Err(e)
+                if attempt < policy.retries
+                    && is_retryable(method, &e)
+                    && started.elapsed() + delay < policy.budget =>
+            {
+                attempt += 1;
+                println!(
+                    "Upstream request failed ({}); retrying in {}ms ({}/{})",
+                    describe_upstream_error(&e),
+                    delay.as_millis(),
+                    attempt,
+                    policy.retries
+                );
+
+                sleep(delay).await;
+                delay = next_delay(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// How long to wait before the retry after one which waited `delay`
+fn next_delay(delay: Duration) -> Duration {
+    delay.saturating_mul(2).min(MAX_RETRY_DELAY)
+}
+
+/// Explain why the local service couldn't be reached, without naming it, so that the
+/// explanation may be shown to visitors
+pub fn describe_upstream_error(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        return "the request timed out";
+    }
+
+    // The interesting part is usually buried a few sources down
+    let mut source = error.source();
+    while let Some(e) = source {
+        if let Some(io_error) = e.downcast_ref::<io::Error>() {
+            match io_error.kind() {
+                io::ErrorKind::ConnectionRefused => return "connection refused",
+                io::ErrorKind::ConnectionReset => return "connection reset",
+                io::ErrorKind::TimedOut => return "the connection timed out",
+                _ => {}
+            }
+        }
+
+        if e.to_string().contains("dns error") {
+            return "the host name couldn't be resolved";
+        }
+
+        source = e.source();
+    }
+
+    // Anything more specific would give away the service's URL
+    if error.is_connect() {
+        "couldn't connect"
+    } else if error.is_body() || error.is_decode() {
+        "the response couldn't be read"
+    } else {
+        "the request failed"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    fn url(listener: &TcpListener) -> Url {
+        let port = listener.local_addr().unwrap().port();
+        Url::from_str(&format!("http://127.0.0.1:{}/", port)).unwrap()
+    }
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            delay: Duration::from_millis(1),
+            budget: Duration::from_secs(10),
+        }
+    }
+
+    /// A service which hangs up on every request, counting how many it was sent
+    fn hanging_up() -> (Url, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let counter = attempts.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+
+        (url, attempts)
+    }
+
+    #[tokio::test]
+    async fn retries_and_describes_refused_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        drop(listener);
+
+        let error = send_with_retries(
+            &Client::new(),
+            &Method::POST,
+            &url,
+            &HeaderMap::new(),
+            b"",
+            policy(0),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(is_retryable(&Method::POST, &error));
+        assert_eq!("connection refused", describe_upstream_error(&error));
+
+        // Nothing is listening for the first attempt, but the service is up for the retry
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let service = url.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let listener = TcpListener::bind(("127.0.0.1", service.port().unwrap())).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            counter.fetch_add(1, Ordering::SeqCst);
+
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let response = send_with_retries(
+            &Client::new(),
+            &Method::POST,
+            &url,
+            &HeaderMap::new(),
+            b"",
+            RetryPolicy {
+                retries: 1,
+                delay: Duration::from_millis(500),
+                budget: Duration::from_secs(10),
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn retries_only_idempotent_requests_which_reached_the_service() {
+        let (url, attempts) = hanging_up();
+        let result = send_with_retries(
+            &Client::new(),
+            &Method::GET,
+            &url,
+            &HeaderMap::new(),
+            b"",
+            policy(2),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(3, attempts.load(Ordering::SeqCst));
+
+        let (url, attempts) = hanging_up();
+        let result = send_with_retries(
+            &Client::new(),
+            &Method::POST,
+            &url,
+            &HeaderMap::new(),
+            b"",
+            policy(2),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(1, attempts.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_visitor_would_have_stopped_waiting() {
+        // A service which accepts every request, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = url(&listener);
+        thread::spawn(move || {
+            let connections: Vec<_> = listener.incoming().collect();
+            drop(connections);
+        });
+
+        let started = Instant::now();
+        let error = send_with_retries(
+            &Client::new(),
+            &Method::GET,
+            &url,
+            &HeaderMap::new(),
+            b"",
+            RetryPolicy {
+                retries: 5,
+                delay: Duration::from_millis(1),
+                budget: Duration::from_millis(300),
+            },
+        )
+        .await
+        .unwrap_err();
+
+        assert!(error.is_timeout());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_a_limit() {
+        assert_eq!(
+            Duration::from_millis(500),
+            next_delay(Duration::from_millis(250))
+        );
+        assert_eq!(MAX_RETRY_DELAY, next_delay(Duration::from_secs(20)));
+        assert_eq!(MAX_RETRY_DELAY, next_delay(Duration::MAX));
+    }
+}
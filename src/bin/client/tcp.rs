@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use request_proxy::types::{Base64Bytes, StreamEvent};
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Most bytes sent to the server in one go
+const MAX_BATCH: usize = 1024 * 1024;
+
+/// Most bytes read from the local service at once
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Connections to the local service, each carrying one a visitor made to the server
+pub struct TcpRelay {
+    /// `host:port` of the local service
+    target: String,
+
+    /// Bytes from the server, for each open connection
+    connections: Mutex<HashMap<Uuid, mpsc::UnboundedSender<Vec<u8>>>>,
+
+    /// What happened to the local ends of connections, for the server
+    events: tokio::sync::Mutex<mpsc::UnboundedReceiver<StreamEvent>>,
+    sender: mpsc::UnboundedSender<StreamEvent>,
+}
+
+impl TcpRelay {
+    pub fn new(target: String) -> TcpRelay {
+        let (sender, events) = mpsc::unbounded_channel();
+
+        TcpRelay {
+            target,
+            connections: Mutex::new(HashMap::new()),
+            events: tokio::sync::Mutex::new(events),
+            sender,
+        }
+    }
+
+    /// Act on something the server said happened to a visitor's connection
+    pub fn handle(&self, event: StreamEvent) {
+        let mut connections = self.connections.lock().unwrap();
+
+        match event {
+            StreamEvent::Open { id, remote_addr } => {
+                let (to_service, from_server) = mpsc::unbounded_channel();
+                connections.insert(id, to_service);
+
+                tokio::spawn(carry(
+                    self.target.clone(),
+                    id,
+                    remote_addr,
+                    from_server,
+                    self.sender.clone(),
+                ));
+            }
+            StreamEvent::Data { id, data } => {
+                if let Some(connection) = connections.get(&id) {
+                    let _ = connection.send(data.0);
+                }
+            }
+            StreamEvent::Close { id } => {
+                connections.remove(&id);
+            }
+        }
+    }
+
+    /// Wait for something to happen to the local ends of connections, returning all that has
+    pub async fn outgoing(&self) -> Vec<StreamEvent> {
+        let mut events = self.events.lock().await;
+        let first = events
+            .recv()
+            .await
+            .expect("The relay keeps a sender of its own");
+
+        let mut batch = vec![first];
+        let mut size = 0;
+        while size < MAX_BATCH {
+            match events.try_recv() {
+                Ok(event) => {
+                    if let StreamEvent::Data { data, .. } = &event {
+                        size += data.0.len();
+                    }
+                    batch.push(event);
+                }
+                Err(_) => break,
+            }
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        for event in &batch {
+            if let StreamEvent::Close { id } = event {
+                connections.remove(id);
+            }
+        }
+
+        batch
+    }
+
+    /// Close every connection, since the server that carried them was lost
+    pub fn close_all(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+}
+
+/// Carry a visitor's connection to the local service until either end closes it
+async fn carry(
+    target: String,
+    id: Uuid,
+    remote_addr: Option<IpAddr>,
+    mut from_server: mpsc::UnboundedReceiver<Vec<u8>>,
+    to_server: mpsc::UnboundedSender<StreamEvent>,
+) {
+    let visitor = remote_addr.map_or("[unknown]".to_string(), |addr| addr.to_string());
+
+    let socket = match TcpStream::connect(&target).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            println!(
+                "Failed to reach {} for connection {} from {}: {}",
+                target, id, visitor, e
+            );
+            let _ = to_server.send(StreamEvent::Close { id });
+            return;
+        }
+    };
+
+    println!("Connection {} from {} opened to {}", id, visitor, target);
+
+    let (mut reader, mut writer) = socket.into_split();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buffer) => match read {
+                Ok(0) | Err(_) => {
+                    let _ = to_server.send(StreamEvent::Close { id });
+                    break;
+                }
+                Ok(n) => {
+                    let _ = to_server.send(StreamEvent::Data {
+                        id,
+                        data: Base64Bytes(buffer[..n].to_vec()),
+                    });
+                }
+            },
+            data = from_server.recv() => match data {
+                Some(data) => {
+                    if writer.write_all(&data).await.is_err() {
+                        let _ = to_server.send(StreamEvent::Close { id });
+                        break;
+                    }
+                }
+                // The visitor closed their end
+                None => break,
+            },
+        }
+    }
+
+    println!("Connection {} closed", id);
+}
+
+/// Parses a port (`5432`), `host:port` (`db.internal:5432`), or URL with a port into the
+/// address of the local service
+pub fn parse_tcp_target(target: &str) -> Result<String, String> {
+    if let Ok(port) = u16::from_str(target) {
+        return Ok(format!("localhost:{}", port));
+    }
+
+    let address = if target.contains("://") {
+        Url::from_str(target).ok().and_then(|url| {
+            Some(format!(
+                "{}:{}",
+                url.host_str()?,
+                url.port_or_known_default()?
+            ))
+        })
+    } else {
+        target
+            .rsplit_once(':')
+            .filter(|(host, port)| !host.is_empty() && u16::from_str(port).is_ok())
+            .map(|_| target.to_string())
+    };
+
+    address.ok_or_else(|| format!("Invalid target '{}': expected a port or host:port", target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tcp_target_accepts_port_host_and_url() {
+        assert_eq!(Ok("localhost:5432".to_string()), parse_tcp_target("5432"));
+        assert_eq!(
+            Ok("db.internal.test:5432".to_string()),
+            parse_tcp_target("db.internal.test:5432")
+        );
+        assert_eq!(
+            Ok("cache.internal.test:6379".to_string()),
+            parse_tcp_target("redis://cache.internal.test:6379")
+        );
+        assert!(parse_tcp_target("db.internal.test").is_err());
+        assert!(parse_tcp_target(":5432").is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use std::str::FromStr;
+
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+
+/// Controls the `Host` header sent to the local service
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostHeader {
+    /// Replace `Host` with the host and port of the local service
+    Rewrite,
+
+    /// Keep the `Host` header the visitor sent to the server
+    Preserve,
+
+    /// Always send this `Host`
+    Fixed(String),
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "rewrite" => Ok(HostHeader::Rewrite),
+            "preserve" => Ok(HostHeader::Preserve),
+            "" => Err(String::from("Host header mode can't be empty")),
+            host => HeaderValue::from_str(host)
+                .map(|_| HostHeader::Fixed(host.to_string()))
+                .map_err(|_| format!("Invalid Host header '{}'", host)),
+        }
+    }
+}
+
+/// A local service to which some of the visitors' requests are forwarded
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    /// Requests whose path starts with this prefix are sent to this service
+    pub prefix: String,
+
+    pub destination: Url,
+    pub host_header: HostHeader,
+
+    /// Point `Location` headers which refer to the service at the public URL instead
+    pub rewrite_location: bool,
+
+    /// Point cookies set for the service's domain at the public host instead
+    pub rewrite_cookies: bool,
+
+    /// Replace links to the service in text bodies with links to the public URL
+    pub rewrite_body: bool,
+}
+
+impl Upstream {
+    /// An upstream receiving every request, with the `Host` rewritten and responses untouched
+    pub fn new(destination: Url) -> Upstream {
+        Upstream {
+            prefix: String::from("/"),
+            destination,
+            host_header: HostHeader::Rewrite,
+            rewrite_location: false,
+            rewrite_cookies: false,
+            rewrite_body: false,
+        }
+    }
+
+    /// The `Host` header to send, or `None` to keep the visitor's
+    pub fn host(&self) -> Option<String> {
+        match &self.host_header {
+            HostHeader::Rewrite => {
+                let mut host = self.destination.host_str()?.to_owned();
+
+                if let Some(port) = self.destination.port() {
+                    host.push_str(&format!(":{}", port));
+                }
+
+                Some(host)
+            }
+            HostHeader::Preserve => None,
+            HostHeader::Fixed(host) => Some(host.clone()),
+        }
+    }
+
+    /// Whether the path lies under this upstream's prefix, at a segment boundary
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    /// Parse a route such as `/api=localhost:4000;host=preserve;rewrite-location;rewrite-body`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(';');
+        let route = options.next().unwrap_or_default();
+
+        let (prefix, target) = route
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid route '{}'; expected PREFIX=TARGET", s))?;
+
+        if !prefix.starts_with('/') {
+            return Err(format!("Route prefix '{}' must start with '/'", prefix));
+        }
+
+        let mut upstream = Upstream {
+            prefix: prefix.to_string(),
+            ..Upstream::new(parse_target(target.trim())?)
+        };
+
+        for option in options.map(|o| o.trim()).filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("host", mode)) => upstream.host_header = HostHeader::from_str(mode)?,
+                None if option == "rewrite-location" => upstream.rewrite_location = true,
+                None if option == "rewrite-cookies" => upstream.rewrite_cookies = true,
+                None if option == "rewrite-body" => upstream.rewrite_body = true,
+                _ => return Err(format!("Unknown route option '{}'", option)),
+            }
+        }
+
+        Ok(upstream)
+    }
+}
+
+/// Pick the upstream with the longest prefix matching `path`
+pub fn route<'a>(upstreams: &'a [Upstream], path: &str) -> Option<&'a Upstream> {
+    upstreams
+        .iter()
+        .filter(|upstream| upstream.matches(path))
+        .max_by_key(|upstream| upstream.prefix.len())
+}
+
+/// Parses a port (`8080`), `host:port` (`localhost:3000`), or full URL into the upstream URL
+pub fn parse_target(target: &str) -> Result<Url, String> {
+    let url = if target.contains("://") {
+        Url::from_str(target)
+    } else if let Ok(port) = u16::from_str(target) {
+        Url::from_str(&format!("http://localhost:{}/", port))
+    } else {
+        Url::from_str(&format!("http://{}/", target))
+    };
+
+    url.map_err(|e| format!("Invalid target '{}': {}", target, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_target_accepts_port_host_and_url() {
+        assert_eq!(
+            "http://localhost:8080/",
+            parse_target("8080").unwrap().as_str()
+        );
+        assert_eq!(
+            "http://localhost:3000/",
+            parse_target("localhost:3000").unwrap().as_str()
+        );
+        assert_eq!(
+            "https://some.internal.service.test/",
+            parse_target("https://some.internal.service.test/")
+                .unwrap()
+                .as_str()
+        );
+        assert!(parse_target("not a host:port").is_err());
+    }
+
+    #[test]
+    fn parses_routes_with_options() {
+        let upstream =
+            Upstream::from_str("/api=localhost:4000;host=api.internal.test;rewrite-cookies")
+                .unwrap();
+
+        assert_eq!("/api", upstream.prefix);
+        assert_eq!("http://localhost:4000/", upstream.destination.as_str());
+        assert_eq!(Some("api.internal.test".to_string()), upstream.host());
+        assert!(upstream.rewrite_cookies);
+        assert!(!upstream.rewrite_location);
+
+        assert!(Upstream::from_str("api=4000").is_err());
+        assert!(Upstream::from_str("/api=4000;bogus").is_err());
+    }
+
+    #[test]
+    fn routes_by_longest_prefix() {
+        let upstreams = vec![
+            Upstream::new(parse_target("8080").unwrap()),
+            Upstream::from_str("/api=4000").unwrap(),
+            Upstream::from_str("/api/v2=4002").unwrap(),
+        ];
+
+        let port = |path| route(&upstreams, path).and_then(|u| u.destination.port());
+
+        assert_eq!(Some(8080), port("/"));
+        assert_eq!(Some(4000), port("/api"));
+        assert_eq!(Some(4000), port("/api/users"));
+        assert_eq!(Some(8080), port("/apiary"));
+        assert_eq!(Some(4002), port("/api/v2/users"));
+        assert!(route(&upstreams[1..], "/other").is_none());
+    }
+}
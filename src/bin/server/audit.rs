@@ -0,0 +1,99 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use uuid::Uuid;
+
+/// Something that happened while authenticating a client or visitor.
+///
+/// Events never include the secrets that were presented.
+pub enum AuthEvent {
+    /// A client presented the correct secret for the first time
+    ClientConnected(Option<Uuid>),
+
+    /// A client said it was disconnecting
+    ClientDisconnected(Option<Uuid>),
+
+    /// A request presented the wrong proxy secret
+    ClientRejected,
+
+    /// A visitor presented credentials which weren't accepted
+    VisitorRejected,
+
+    /// A visitor logged in through the login page
+    VisitorLoggedIn,
+
+    /// An address failed to authenticate too often, and is refused for a while
+    LockedOut(Duration),
+
+    /// A locked-out address tried to authenticate again
+    LockoutRefused,
+}
+
+impl AuthEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            AuthEvent::ClientConnected(_) => "client_connected",
+            AuthEvent::ClientDisconnected(_) => "client_disconnected",
+            AuthEvent::ClientRejected => "client_rejected",
+            AuthEvent::VisitorRejected => "visitor_rejected",
+            AuthEvent::VisitorLoggedIn => "visitor_logged_in",
+            AuthEvent::LockedOut(_) => "locked_out",
+            AuthEvent::LockoutRefused => "lockout_refused",
+        }
+    }
+}
+
+/// Records authentication events as JSON lines, to a file or standard output
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// Append to the file at `path`, or print to standard output if there isn't one
+    pub fn open(path: Option<&Path>) -> io::Result<AuditLog> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+
+        Ok(AuditLog { file })
+    }
+
+    pub fn record(&self, ip: IpAddr, event: AuthEvent) {
+        let mut entry = json!({
+            "time": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            "event": event.name(),
+            "ip": ip.to_string(),
+        });
+
+        match event {
+            AuthEvent::ClientConnected(Some(id)) | AuthEvent::ClientDisconnected(Some(id)) => {
+                entry["client_id"] = json!(id.hyphenated().to_string());
+            }
+            AuthEvent::LockedOut(duration) => {
+                entry["duration"] = json!(duration.as_secs());
+            }
+            _ => {}
+        }
+
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().unwrap();
+                if let Err(e) = writeln!(file, "{}", entry) {
+                    eprintln!("Failed to write to the audit log: {}", e);
+                }
+            }
+            None => println!("audit: {}", entry),
+        }
+    }
+}
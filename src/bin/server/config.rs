@@ -0,0 +1,446 @@
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use base64::{engine::general_purpose, Engine};
+use hyper::header::HeaderName;
+use hyper::StatusCode;
+use rand::Rng;
+
+use request_proxy::acl::{AccessList, IpNetwork};
+use request_proxy::auth::Lockout;
+use request_proxy::headers::{parse_header_rules, HeaderRule};
+use request_proxy::mock::{parse_mock_routes, MockResponse, MockRoute};
+use request_proxy::protocol::{Capability, CAPABILITIES};
+use request_proxy::queue::{parse_path_priority, PriorityRules};
+use request_proxy::ratelimit::RateLimit;
+use request_proxy::visitor::TrustedIpHeader;
+use request_proxy::webhook::WebhookVerifier;
+
+use crate::visitor_auth::VisitorAuthConfig;
+
+/// Server settings, read from environment variables (or a `.env` file)
+pub struct Config {
+    /// Address on which to listen for visitors and clients
+    pub listen_addr: SocketAddr,
+
+    /// Separate address on which to listen for clients, if they're kept apart from visitors
+    pub control_addr: Option<SocketAddr>,
+
+    /// Address on which to accept raw TCP connections for `client tcp`, if at all
+    pub tcp_addr: Option<SocketAddr>,
+
+    /// Also accept clients which send control requests to any path, with the secret in a header
+    pub legacy_control: bool,
+
+    /// Capabilities clients must agree to in their hello before they're handed any requests
+    pub required_capabilities: Vec<Capability>,
+
+    /// Shared secret key which clients must present
+    pub secret: String,
+
+    /// Whether `secret` was generated because `$PROXY_SECRET` wasn't set
+    pub secret_generated: bool,
+
+    /// How long to wait for clients to answer in-flight requests when shutting down
+    pub shutdown_timeout: Duration,
+
+    /// How long a client has to answer a request before an idempotent one is handed to
+    /// another client, if ever
+    pub lease_timeout: Option<Duration>,
+
+    pub limits: Limits,
+
+    /// Which queued requests are handed to clients first
+    pub priority_rules: PriorityRules,
+
+    /// Header holding the visitor's real address, when behind a trusted reverse proxy
+    pub trusted_ip_header: TrustedIpHeader,
+
+    /// Which visitor addresses may use the tunnel
+    pub access_list: AccessList,
+
+    /// How quickly each visitor IP may send requests
+    pub rate_limit_per_ip: Option<RateLimit>,
+
+    /// How quickly requests may be sent through the tunnel as a whole
+    pub rate_limit_tunnel: Option<RateLimit>,
+
+    /// Credentials visitors must present before their requests are forwarded
+    pub visitor_auth: VisitorAuthConfig,
+
+    /// When to lock out addresses which keep failing to authenticate, if at all
+    pub auth_lockout: Option<Lockout>,
+
+    /// File to which authentication events are appended, instead of standard output
+    pub audit_log: Option<PathBuf>,
+
+    /// Changes made to the headers of every response sent to visitors
+    pub response_header_rules: Vec<HeaderRule>,
+
+    /// Signature checks for incoming webhooks, if enabled
+    pub webhook: Option<WebhookConfig>,
+
+    /// Which requests are acknowledged and stored for the client, instead of forwarded live
+    pub capture: Option<CaptureConfig>,
+
+    /// Requests the server answers itself, without involving the client
+    pub mock_routes: Vec<MockRoute>,
+
+    /// Response sent to visitors while no client is connected, instead of queueing their request
+    pub offline_response: Option<MockResponse>,
+}
+
+/// Requests the server answers on the client's behalf, keeping them on disk until a client
+/// collects them
+pub struct CaptureConfig {
+    /// Directory holding the captured requests, and the client's responses to them
+    pub dir: PathBuf,
+
+    /// Path prefixes to capture; if empty, every request is captured
+    pub paths: Vec<String>,
+
+    /// Only capture requests while no client is connected
+    pub only_offline: bool,
+
+    /// Response sent to visitors whose request was captured
+    pub status: StatusCode,
+    pub content_type: String,
+    pub body: String,
+
+    /// Maximum number of captured requests waiting for a client
+    pub max_requests: usize,
+}
+
+/// Which requests must carry a valid webhook signature, and how to check it
+pub struct WebhookConfig {
+    pub verifier: WebhookVerifier,
+
+    /// Path prefixes receiving webhooks; if empty, every request is checked
+    pub paths: Vec<String>,
+}
+
+/// Bounds on how much visitor traffic the server will hold on to at once
+pub struct Limits {
+    /// Maximum number of visitor requests waiting to be picked up by a client
+    pub max_queued_requests: usize,
+
+    /// Maximum size, in bytes, of a visitor request body
+    pub max_body_size: usize,
+
+    /// Maximum number of client responses waiting to be collected by visitors
+    pub max_pending_responses: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        // Read the port on which to listen.
+        let port: u16 = env_or("PORT", 3000);
+
+        // Read the IP address on which to listen
+        let ip: IpAddr = env_or("LISTEN_IP", IpAddr::from([127, 0, 0, 1]));
+
+        // Get the configured $PROXY_SECRET or generate a one-time random key.
+        let (secret, secret_generated) = match env::var("PROXY_SECRET") {
+            Ok(secret) => (secret, false),
+            Err(_) => (
+                general_purpose::STANDARD.encode(rand::thread_rng().gen::<[u8; 30]>()),
+                true,
+            ),
+        };
+
+        // Clients may be served on their own port, eg: one that isn't exposed publicly
+        let control_addr = env::var("CONTROL_PORT")
+            .ok()
+            .filter(|port| !port.is_empty())
+            .map(|port| {
+                let port = u16::from_str(&port)
+                    .unwrap_or_else(|_| panic!("Failed to parse $CONTROL_PORT!"));
+                SocketAddr::new(env_or("CONTROL_LISTEN_IP", ip), port)
+            });
+
+        // Raw TCP connections, eg: to a database, are accepted on a port of their own
+        let tcp_addr = env::var("TCP_PORT")
+            .ok()
+            .filter(|port| !port.is_empty())
+            .map(|port| {
+                let port =
+                    u16::from_str(&port).unwrap_or_else(|_| panic!("Failed to parse $TCP_PORT!"));
+                SocketAddr::new(env_or("TCP_LISTEN_IP", ip), port)
+            });
+
+        Config {
+            listen_addr: SocketAddr::new(ip, port),
+            control_addr,
+            tcp_addr,
+            legacy_control: env_or("LEGACY_CONTROL", false),
+            required_capabilities: env_list("REQUIRED_CAPABILITIES")
+                .iter()
+                .map(|name| match Capability::from_str(name) {
+                    Ok(capability) if CAPABILITIES.contains(&capability) => capability,
+                    Ok(capability) => panic!(
+                        "Failed to parse $REQUIRED_CAPABILITIES! This server doesn't support {}",
+                        capability
+                    ),
+                    Err(e) => panic!("Failed to parse $REQUIRED_CAPABILITIES! {}", e),
+                })
+                .collect(),
+            secret,
+            secret_generated,
+            shutdown_timeout: Duration::from_secs(env_or("SHUTDOWN_TIMEOUT", 10)),
+            lease_timeout: Some(env_or("LEASE_TIMEOUT", 0))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            limits: Limits {
+                max_queued_requests: env_or("MAX_QUEUED_REQUESTS", 100),
+                max_body_size: env_or("MAX_BODY_SIZE", 10 * 1024 * 1024),
+                max_pending_responses: env_or("MAX_PENDING_RESPONSES", 100),
+            },
+            priority_rules: PriorityRules {
+                paths: env_list("PRIORITY_PATHS")
+                    .iter()
+                    .map(|rule| {
+                        parse_path_priority(rule)
+                            .unwrap_or_else(|e| panic!("Failed to parse $PRIORITY_PATHS! {}", e))
+                    })
+                    .collect(),
+                header: env::var("PRIORITY_HEADER")
+                    .ok()
+                    .filter(|name| !name.is_empty())
+                    .map(|name| {
+                        HeaderName::from_str(&name)
+                            .unwrap_or_else(|e| panic!("Failed to parse $PRIORITY_HEADER! {}", e))
+                    }),
+                header_overrides: env_or("PRIORITY_HEADER_OVERRIDES", false),
+            },
+            trusted_ip_header: env_or("TRUSTED_IP_HEADER", TrustedIpHeader::None),
+            access_list: AccessList {
+                allow: networks_from_env("ALLOW_CIDRS"),
+                deny: networks_from_env("DENY_CIDRS"),
+            },
+            rate_limit_per_ip: rate_limit_from_env("RATE_LIMIT_PER_IP", "RATE_LIMIT_PER_IP_BURST"),
+            rate_limit_tunnel: rate_limit_from_env("RATE_LIMIT_TUNNEL", "RATE_LIMIT_TUNNEL_BURST"),
+            visitor_auth: VisitorAuthConfig {
+                basic_credentials: env_list("VISITOR_BASIC_AUTH")
+                    .into_iter()
+                    .map(|pair| match pair.split_once(':') {
+                        Some((user, pass)) => (user.to_string(), pass.to_string()),
+                        None => {
+                            panic!("Failed to parse $VISITOR_BASIC_AUTH! Expected user:password")
+                        }
+                    })
+                    .collect(),
+                bearer_tokens: env_list("VISITOR_BEARER_TOKENS"),
+                login_secret: env::var("VISITOR_LOGIN_SECRET")
+                    .ok()
+                    .filter(|s| !s.is_empty()),
+                session_ttl: Duration::from_secs(env_or("VISITOR_SESSION_TTL", 24 * 60 * 60)),
+            },
+            webhook: webhook_from_env(),
+            capture: capture_from_env(),
+            mock_routes: parse_mock_routes(&env::var("MOCK_ROUTES").unwrap_or_default())
+                .unwrap_or_else(|e| panic!("Failed to parse $MOCK_ROUTES! {}", e)),
+            offline_response: env::var("OFFLINE_RESPONSE")
+                .ok()
+                .filter(|response| !response.trim().is_empty())
+                .map(|response| {
+                    MockResponse::from_str(&response)
+                        .unwrap_or_else(|e| panic!("Failed to parse $OFFLINE_RESPONSE! {}", e))
+                }),
+            auth_lockout: lockout_from_env(),
+            audit_log: env::var("AUTH_AUDIT_LOG")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            response_header_rules: parse_header_rules(
+                &env::var("RESPONSE_HEADER_RULES").unwrap_or_default(),
+            )
+            .unwrap_or_else(|e| panic!("Failed to parse $RESPONSE_HEADER_RULES! {}", e)),
+        }
+    }
+
+    /// The settings `from_env` reads from an empty environment, with the given secret, so tests
+    /// don't depend on the environment they run in
+    #[cfg(test)]
+    pub fn for_tests(secret: &str) -> Config {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        Config {
+            listen_addr: SocketAddr::new(ip, 3000),
+            control_addr: None,
+            tcp_addr: None,
+            legacy_control: false,
+            required_capabilities: Vec::new(),
+            secret: secret.to_string(),
+            secret_generated: false,
+            shutdown_timeout: Duration::from_secs(10),
+            lease_timeout: None,
+            limits: Limits {
+                max_queued_requests: 100,
+                max_body_size: 10 * 1024 * 1024,
+                max_pending_responses: 100,
+            },
+            priority_rules: PriorityRules::default(),
+            trusted_ip_header: TrustedIpHeader::None,
+            access_list: AccessList::default(),
+            rate_limit_per_ip: None,
+            rate_limit_tunnel: None,
+            visitor_auth: VisitorAuthConfig {
+                basic_credentials: Vec::new(),
+                bearer_tokens: Vec::new(),
+                login_secret: None,
+                session_ttl: Duration::from_secs(24 * 60 * 60),
+            },
+            auth_lockout: Some(Lockout {
+                max_failures: 5,
+                duration: Duration::from_secs(60),
+                max_duration: Duration::from_secs(60 * 60),
+            }),
+            audit_log: None,
+            response_header_rules: Vec::new(),
+            webhook: None,
+            capture: None,
+            mock_routes: Vec::new(),
+            offline_response: None,
+        }
+    }
+}
+
+/// Read a comma-separated list from the environment variable `name`
+pub fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// Read a comma-separated list of CIDR blocks from the environment variable `name`
+fn networks_from_env(name: &str) -> Vec<IpNetwork> {
+    env_list(name)
+        .iter()
+        .map(|network| {
+            IpNetwork::from_str(network)
+                .unwrap_or_else(|e| panic!("Failed to parse ${}! {}", name, e))
+        })
+        .collect()
+}
+
+/// Read a rate limit of `rate_var` requests per second, with bursts of up to `burst_var`.
+///
+/// The limit is disabled when the rate isn't set, or is 0.
+fn rate_limit_from_env(rate_var: &str, burst_var: &str) -> Option<RateLimit> {
+    let rate: f64 = env_or(rate_var, 0.0);
+
+    if !rate.is_finite() || rate < 0.0 {
+        panic!("Failed to parse ${}! Expected a positive number", rate_var);
+    }
+
+    if rate == 0.0 {
+        return None;
+    }
+
+    let burst: f64 = env_or(burst_var, rate);
+
+    if !burst.is_finite() || burst < 0.0 {
+        panic!("Failed to parse ${}! Expected a positive number", burst_var);
+    }
+
+    Some(RateLimit {
+        rate,
+        burst: burst.max(1.0),
+    })
+}
+
+/// Read when to lock out addresses which fail to authenticate; disabled if `$AUTH_MAX_FAILURES` is 0
+fn lockout_from_env() -> Option<Lockout> {
+    let max_failures: u32 = env_or("AUTH_MAX_FAILURES", 5);
+
+    if max_failures == 0 {
+        return None;
+    }
+
+    Some(Lockout {
+        max_failures,
+        duration: Duration::from_secs(env_or("AUTH_LOCKOUT", 60)),
+        max_duration: Duration::from_secs(env_or("AUTH_LOCKOUT_MAX", 60 * 60)),
+    })
+}
+
+/// Read the webhook verification settings, starting from the `$WEBHOOK_PRESET` provider.
+///
+/// Verification is disabled unless `$WEBHOOK_SECRET` is set.
+fn webhook_from_env() -> Option<WebhookConfig> {
+    let secret = env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty())?;
+    let preset = env::var("WEBHOOK_PRESET").unwrap_or_else(|_| String::from("custom"));
+
+    let mut verifier = WebhookVerifier::preset(&preset, secret)
+        .unwrap_or_else(|e| panic!("Failed to parse $WEBHOOK_PRESET! {}", e));
+
+    if let Ok(header) = env::var("WEBHOOK_HEADER") {
+        verifier.header = header.to_ascii_lowercase();
+    }
+
+    verifier.algorithm = env_or("WEBHOOK_ALGORITHM", verifier.algorithm);
+    verifier.encoding = env_or("WEBHOOK_ENCODING", verifier.encoding);
+    verifier.payload = env_or("WEBHOOK_PAYLOAD", verifier.payload);
+
+    if let Ok(prefix) = env::var("WEBHOOK_SIGNATURE_PREFIX") {
+        verifier.prefix = prefix;
+    }
+
+    if let Ok(header) = env::var("WEBHOOK_TIMESTAMP_HEADER") {
+        verifier.timestamp_header = Some(header.to_ascii_lowercase()).filter(|h| !h.is_empty());
+    }
+
+    if let Ok(tolerance) = env::var("WEBHOOK_TOLERANCE") {
+        let tolerance: u64 = u64::from_str(&tolerance)
+            .unwrap_or_else(|_| panic!("Failed to parse $WEBHOOK_TOLERANCE!"));
+        verifier.tolerance = Some(Duration::from_secs(tolerance)).filter(|t| !t.is_zero());
+    }
+
+    if verifier.header.is_empty() {
+        panic!("$WEBHOOK_HEADER must be set when using the custom webhook preset!");
+    }
+
+    Some(WebhookConfig {
+        verifier,
+        paths: env_list("WEBHOOK_PATHS"),
+    })
+}
+
+/// Read where and when to capture requests; disabled unless `$CAPTURE_DIR` is set
+fn capture_from_env() -> Option<CaptureConfig> {
+    let dir = env::var("CAPTURE_DIR").ok().filter(|dir| !dir.is_empty())?;
+
+    let only_offline = match env::var("CAPTURE_WHEN").as_deref() {
+        Err(_) | Ok("always") => false,
+        Ok("offline") => true,
+        Ok(_) => panic!("Failed to parse $CAPTURE_WHEN! Expected always or offline"),
+    };
+
+    Some(CaptureConfig {
+        dir: PathBuf::from(dir),
+        paths: env_list("CAPTURE_PATHS"),
+        only_offline,
+        status: env_or("CAPTURE_STATUS", StatusCode::ACCEPTED),
+        content_type: env::var("CAPTURE_CONTENT_TYPE")
+            .unwrap_or_else(|_| String::from("text/plain; charset=utf-8")),
+        body: env::var("CAPTURE_BODY").unwrap_or_else(|_| String::from("📥 Accepted")),
+        max_requests: env_or("CAPTURE_MAX_REQUESTS", 1000),
+    })
+}
+
+/// Read and parse the environment variable `name`, or use `default` if it isn't set.
+///
+/// Panics if the variable is set, but can't be parsed.
+pub fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => T::from_str(&value).unwrap_or_else(|_| panic!("Failed to parse ${}!", name)),
+        Err(_) => default,
+    }
+}
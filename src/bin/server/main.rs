@@ -0,0 +1,2579 @@
+extern crate base64;
+extern crate dotenv;
+extern crate futures;
+extern crate hyper;
+extern crate request_proxy;
+extern crate serde;
+extern crate serde_json;
+extern crate uuid;
+#[macro_use]
+extern crate failure;
+extern crate rand;
+extern crate tokio;
+
+use request_proxy::auth::{secrets_match, FailureTracker};
+use request_proxy::capture::CaptureStore;
+use request_proxy::dispatch::{is_idempotent, InFlight, Rejection};
+use request_proxy::headers::{apply_header_rules, strip_control_headers, strip_hop_by_hop};
+use request_proxy::mock::{mock_response, public_mock_response};
+use request_proxy::protocol::{
+    agree, compress, decompress, Capability, Endpoint, Hello, Versions, WireFormat, CAPABILITIES,
+    CONTROL_PREFIX, MAX_DECOMPRESSED_MESSAGE, PROTOCOL_VERSIONS, RESPONSE_TIMEOUT, VERSIONS_PATH,
+};
+use request_proxy::queue::{FairQueue, Priority};
+use request_proxy::ratelimit::{Limited, RateLimit, RateLimiter};
+use request_proxy::signal::shutdown_signal;
+use request_proxy::types::*;
+use request_proxy::visitor::{visitor_ip, TrustedIpHeader};
+use request_proxy::webhook::WebhookError;
+
+use std::collections::HashMap;
+use std::io::{self, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::*;
+
+use futures::future::{Future, FutureExt, TryFutureExt};
+use futures::task::{Context, Poll};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+use hyper::body::{self, Bytes, HttpBody};
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Server, StatusCode};
+use hyper::{Request, Response};
+
+use failure::Fail;
+use serde_json::json;
+use uuid::Uuid;
+
+use dotenv::dotenv;
+
+mod audit;
+mod config;
+mod tcp;
+mod visitor_auth;
+use audit::{AuditLog, AuthEvent};
+use config::Config;
+use tcp::{relay, Streams};
+use visitor_auth::VisitorAuth;
+
+// `failure_derive` generates its impls inside a constant, which newer compilers warn about.
+#[allow(non_local_definitions)]
+pub mod error {
+    use std::convert::From;
+    pub use tokio::time::error::Elapsed as TimeoutError;
+
+    #[derive(Debug, Fail)]
+    pub enum Error {
+        #[fail(display = "Timeout connecting to gateway")]
+        TokioTimeoutError(),
+
+        #[fail(display = "Hyper error: {}", _0)]
+        HyperError(hyper::Error),
+    }
+
+    impl From<TimeoutError> for Error {
+        fn from(_: TimeoutError) -> Self {
+            Error::TokioTimeoutError()
+        }
+    }
+
+    impl From<hyper::Error> for Error {
+        fn from(e: hyper::Error) -> Self {
+            Error::HyperError(e)
+        }
+    }
+}
+
+struct ProxiedResponse {
+    request_id: Uuid,
+    responses: Arc<Mutex<HashMap<Uuid, Response<::hyper::Body>>>>,
+}
+
+impl Future for ProxiedResponse {
+    type Output = Response<Body>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker = cx.waker();
+        self.responses
+            .try_lock()
+            .map(|mut res| match res.remove(&self.request_id) {
+                Some(response) => Poll::Ready(response),
+                None => {
+                    waker.wake_by_ref();
+                    Poll::Pending
+                }
+            })
+            .or_else(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                waker.wake_by_ref();
+                Ok::<_, error::Error>(Poll::Pending)
+            })
+            .unwrap()
+    }
+}
+
+/// Where a visitor request came from, kept with the request while it's queued
+#[derive(Clone)]
+struct Origin {
+    addr: IpAddr,
+    scheme: String,
+    host: Option<String>,
+}
+
+/// Visitor requests waiting to be picked up by a client, with their bodies already read,
+/// taking turns between visitor addresses
+type RequestQueue = FairQueue<IpAddr, Request<Bytes>>;
+
+/// Describe a queued request as the `ProxiedRequest` sent to clients
+fn proxied_request(request_id: Uuid, req: &Request<Bytes>) -> ProxiedRequest<'_> {
+    let origin = req.extensions().get::<Origin>();
+
+    ProxiedRequest {
+        id: request_id,
+        method: req.method().as_ref(),
+        uri: RequestUri {
+            path: req.uri().path().to_string(),
+            query: req.uri().query().map(|q| q.to_string()),
+            fragment: None, // it appears http::request::Uri does not support fragment
+        },
+        version: format!("{:?}", req.version()),
+        headers: req
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), Base64Bytes(value.as_bytes().to_vec())))
+            .collect(),
+        body: Base64Bytes(req.body().to_vec()),
+        remote_addr: origin.map(|o| o.addr),
+        scheme: origin.map(|o| o.scheme.clone()),
+        host: origin.and_then(|o| o.host.clone()),
+    }
+}
+
+/// Copy a queued request, so it can be handed out again if the first client doesn't answer
+fn copy_request(req: &Request<Bytes>) -> Request<Bytes> {
+    let mut copy = Request::new(req.body().clone());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+
+    if let Some(origin) = req.extensions().get::<Origin>() {
+        copy.extensions_mut().insert(origin.clone());
+    }
+
+    copy
+}
+
+#[derive(Clone)]
+struct RequestProxy {
+    config: Arc<Config>,
+    requests: Arc<Mutex<RequestQueue>>,
+    responses: Arc<Mutex<HashMap<Uuid, Response<::hyper::Body>>>>,
+
+    /// Requests which have been handed to a client, but not yet answered,
+    /// with a copy of those which may be handed to another client
+    in_flight: Arc<Mutex<InFlight<Request<Bytes>>>>,
+
+    /// Clients which identified themselves, and when they were last heard from
+    clients: Arc<Mutex<HashMap<Uuid, Instant>>>,
+
+    /// Capabilities agreed with each client which said hello
+    sessions: Arc<Mutex<HashMap<Uuid, Vec<Capability>>>>,
+
+    /// Set once the server is shutting down and should no longer accept visitor requests
+    draining: Arc<AtomicBool>,
+
+    ip_rate_limiter: Option<Arc<Mutex<RateLimiter<IpAddr>>>>,
+    tunnel_rate_limiter: Option<Arc<Mutex<RateLimiter<()>>>>,
+
+    /// Set when visitors have to authenticate before their requests are forwarded
+    visitor_auth: Option<Arc<VisitorAuth>>,
+
+    /// Failed attempts at the client secret per address, when lockouts are enabled
+    client_auth_failures: Option<Arc<Mutex<FailureTracker<IpAddr>>>>,
+
+    /// Failed attempts at visitor credentials per address, kept apart so that visitors
+    /// sharing an address with the client can't lock it out
+    visitor_auth_failures: Option<Arc<Mutex<FailureTracker<IpAddr>>>>,
+
+    audit_log: Arc<AuditLog>,
+
+    /// Requests acknowledged on the client's behalf, when capturing is enabled
+    capture: Option<Arc<Mutex<CaptureStore>>>,
+
+    /// Visitors' raw TCP connections, when the server accepts them
+    streams: Option<Arc<Streams>>,
+
+    /// When the server started, for the health check
+    started: Instant,
+}
+
+/// Reserved paths answered by the server for probes, without a secret and never forwarded
+const HEALTH_PATH: &str = "/_proxy/health";
+const READY_PATH: &str = "/_proxy/ready";
+
+/// Whose credentials are being checked, since each kind is locked out separately
+#[derive(Clone, Copy, PartialEq)]
+enum Credentials {
+    /// The secret clients present in `x-proxy-secret`
+    Client,
+
+    /// The `VISITOR_*` credentials
+    Visitor,
+}
+
+/// Which kind of traffic a listener accepts
+#[derive(Clone, Copy, PartialEq)]
+enum Listener {
+    /// Visitors and clients alike
+    Combined,
+
+    /// Only visitors, since clients have a listener of their own
+    Public,
+
+    /// Only clients
+    Control,
+}
+
+/// How long after its last poll a client still counts as connected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client has to answer a captured request before it's delivered again
+const CAPTURE_REDELIVERY: Duration = Duration::from_secs(60);
+
+/// How long answered and expired requests are remembered, to recognise late and duplicate responses
+const SETTLED_RETENTION: Duration = Duration::from_secs(300);
+
+/// Largest login form the server will read
+const MAX_LOGIN_FORM_SIZE: usize = 4096;
+
+/// How long a client asking about TCP connections waits for something to happen to them
+const STREAM_POLL_WAIT: Duration = Duration::from_secs(5);
+
+/// Seconds after which rejected callers are told to try again
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// Response sent when the server is holding as much as it's allowed to
+fn server_busy_response(message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("retry-after", RETRY_AFTER_SECS)
+        .body(Body::from(message))
+        .unwrap()
+}
+
+/// Response sent to clients using a control endpoint with the wrong method
+fn method_not_allowed_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header("content-type", "text/plain")
+        .body(Body::from("😬 You suck at computers"))
+        .unwrap()
+}
+
+/// Response sent to clients which can't speak to this server, explaining why
+fn incompatible_client_response(reason: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UPGRADE_REQUIRED)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(format!("🧩 {}", reason)))
+        .unwrap()
+}
+
+/// Response sent for paths a listener doesn't serve
+fn not_found_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from("🔍 Not found"))
+        .unwrap()
+}
+
+/// Response sent to visitors whose address isn't allowed to use the tunnel
+fn forbidden_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from("🚫 Forbidden"))
+        .unwrap()
+}
+
+/// Response sent to a client answering a request which isn't waiting for an answer
+fn rejected_response_response(rejection: Rejection) -> Response<Body> {
+    let (status, emoji) = match rejection {
+        Rejection::Unknown => (StatusCode::NOT_FOUND, "🤷"),
+        Rejection::AlreadyAnswered => (StatusCode::CONFLICT, "🔁"),
+        Rejection::Expired => (StatusCode::GONE, "⌛"),
+    };
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(format!(
+            "{} Response rejected: {}",
+            emoji, rejection
+        )))
+        .unwrap()
+}
+
+/// Response sent to visitors who are sending requests faster than they're allowed to
+fn rate_limited_response(limit: &RateLimit, limited: &Limited) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header(
+            "retry-after",
+            limited.retry_after.as_secs_f64().ceil() as u64,
+        )
+        .header("x-ratelimit-limit", limit.burst as u64)
+        .header("x-ratelimit-remaining", 0)
+        .header(
+            "x-ratelimit-reset",
+            limited.reset_after.as_secs_f64().ceil() as u64,
+        )
+        .body(Body::from("🐌 Slow down! Too many requests"))
+        .unwrap()
+}
+
+/// Response sent when a webhook's signature can't be verified
+fn invalid_webhook_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from("🪝 Invalid webhook signature"))
+        .unwrap()
+}
+
+/// Response sent to visitors whose request body exceeds the configured limit
+fn payload_too_large_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("connection", "close")
+        .body(Body::from("🐘 Request body is too large"))
+        .unwrap()
+}
+
+/// Read a body into memory, or return `None` if it's larger than `limit` bytes
+async fn read_body_limited(mut body: Body, limit: usize) -> Result<Option<Bytes>, hyper::Error> {
+    let mut bytes = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    Ok(Some(Bytes::from(bytes)))
+}
+
+/// Response sent to visitors whose request can't be handled because the server is shutting down
+fn shutting_down_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("connection", "close")
+        .body(Body::from("🛑 The proxy server is shutting down"))
+        .unwrap()
+}
+
+/// Response sent to visitors whose request was abandoned because the client went away
+fn client_disconnected_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from("🔌 No client is connected to the proxy"))
+        .unwrap()
+}
+
+/// Response sent to addresses which failed to authenticate too many times
+fn locked_out_response(retry_after: Duration) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("content-type", "text/plain; charset=utf-8")
+        .header("retry-after", retry_after.as_secs_f64().ceil() as u64)
+        .body(Body::from("🔐 Too many failed attempts; try again later"))
+        .unwrap()
+}
+
+/// Response sent to clients which predate the control endpoints, while they aren't accepted
+fn outdated_client_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UPGRADE_REQUIRED)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(format!(
+            "🆙 Upgrade your client; control endpoints moved to {}v1",
+            CONTROL_PREFIX
+        )))
+        .unwrap()
+}
+
+/// Response sent to clients polling for TCP connections without saying who they are
+fn anonymous_stream_client_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from(
+            "🪪 Clients carrying TCP connections must send x-proxy-client-id",
+        ))
+        .unwrap()
+}
+
+/// Read the ID a client sends to identify itself, if any
+fn client_id<T>(request: &Request<T>) -> Option<Uuid> {
+    request
+        .headers()
+        .get("x-proxy-client-id")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| Uuid::parse_str(h).ok())
+}
+
+impl RequestProxy {
+    fn new(
+        config: Config,
+        audit_log: AuditLog,
+        capture: Option<Arc<Mutex<CaptureStore>>>,
+    ) -> RequestProxy {
+        RequestProxy {
+            ip_rate_limiter: config
+                .rate_limit_per_ip
+                .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit)))),
+            tunnel_rate_limiter: config
+                .rate_limit_tunnel
+                .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit)))),
+            visitor_auth: if config.visitor_auth.is_enabled() {
+                Some(Arc::new(VisitorAuth::new(config.visitor_auth.clone())))
+            } else {
+                None
+            },
+            client_auth_failures: config
+                .auth_lockout
+                .map(|lockout| Arc::new(Mutex::new(FailureTracker::new(lockout)))),
+            visitor_auth_failures: config
+                .auth_lockout
+                .map(|lockout| Arc::new(Mutex::new(FailureTracker::new(lockout)))),
+            audit_log: Arc::new(audit_log),
+            capture,
+            streams: config.tcp_addr.map(|_| Arc::new(Streams::new())),
+            started: Instant::now(),
+            in_flight: Arc::new(Mutex::new(InFlight::new(
+                RESPONSE_TIMEOUT,
+                SETTLED_RETENTION,
+                config.lease_timeout,
+            ))),
+            config: Arc::new(config),
+            requests: Arc::new(Mutex::new(FairQueue::new())),
+            responses: Arc::new(Mutex::new(HashMap::new())),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            draining: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    async fn call(
+        &self,
+        req: Request<Body>,
+        peer: SocketAddr,
+        listener: Listener,
+    ) -> Result<Response<Body>, error::Error> {
+        if matches!(req.uri().path(), HEALTH_PATH | READY_PATH) {
+            return Ok(self.probe_response(req.uri().path()).await);
+        }
+
+        let ip = visitor_ip(peer, req.headers(), &self.config.trusted_ip_header);
+        let serves_clients = listener != Listener::Public;
+
+        // Requests to the control endpoints always come from clients; the paths are reserved
+        // even where clients aren't served, so visitors can't reach them by mistake.
+        if req.uri().path().starts_with(CONTROL_PREFIX) {
+            if !serves_clients {
+                return Ok(not_found_response());
+            }
+
+            if let Some(response) = self.authenticate_client(&req, ip).await {
+                return Ok(response);
+            }
+
+            return self.handle_control_request(req, ip).await;
+        }
+
+        // Older clients send their requests anywhere, identified only by the secret header
+        let is_legacy_client = serves_clients
+            && self.config.legacy_control
+            && req.headers().contains_key("x-proxy-secret");
+
+        if is_legacy_client {
+            if let Some(response) = self.authenticate_client(&req, ip).await {
+                return Ok(response);
+            }
+
+            return self.handle_proxy_client_request(req, ip).await;
+        }
+
+        // An older client would otherwise have its polls queued for itself until they time out.
+        // Only the right secret gives it away, so visitors sending the header are unaffected.
+        if serves_clients && self.presents_client_secret(&req) {
+            println!("Turning away outdated client at {}", ip);
+            return Ok(outdated_client_response());
+        }
+
+        if listener == Listener::Control {
+            return Ok(not_found_response());
+        }
+
+        // Otherwise, this is an external request to be forwarded to the client.
+        let mut response = self.handle_visitor_request(req, ip).await?;
+        apply_header_rules(&self.config.response_header_rules, response.headers_mut());
+        Ok(response)
+    }
+
+    /// Whether the request carries the secret clients present
+    fn presents_client_secret(&self, req: &Request<Body>) -> bool {
+        req.headers()
+            .get("x-proxy-secret")
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|key| secrets_match(key, &self.config.secret))
+    }
+
+    /// Check the secret a client presented, returning the response to send if it isn't right
+    async fn authenticate_client(&self, req: &Request<Body>, ip: IpAddr) -> Option<Response<Body>> {
+        // Don't even look at the key if this address has been guessing too often.
+        if let Some(response) = self.check_lockout(ip, Credentials::Client).await {
+            return Some(response);
+        }
+
+        match req.headers().get("x-proxy-secret").map(|h| h.to_str()) {
+            // The secret key is correct, so the client may go ahead.
+            Some(Ok(key)) if secrets_match(key, &self.config.secret) => {
+                self.record_auth_success(ip, Credentials::Client).await;
+                None
+            }
+
+            // The secret key is missing or incorrect.
+            Some(Ok(_)) | None => {
+                println!("Incorrect secret key from {}!", ip);
+                self.record_auth_failure(ip, Credentials::Client, AuthEvent::ClientRejected)
+                    .await;
+
+                Some(
+                    Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .header("content-type", "text/plain; charset=utf-8")
+                        .body(Body::from("🐸 GET OUT".to_string()))
+                        .unwrap(),
+                )
+            }
+
+            // If the secret key header was sent, but we failed to read the value as a String.
+            Some(Err(e)) => {
+                eprintln!("Error decoding Secret Key: {}", e);
+
+                Some(
+                    Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("content-type", "text/plain; charset=utf-8")
+                        .body(Body::from(
+                            "🤢 Your request was bad and you should feel bad",
+                        ))
+                        .unwrap(),
+                )
+            }
+        }
+    }
+
+    /// Handle an authenticated request to one of the versioned control endpoints
+    async fn handle_control_request(
+        &self,
+        request: Request<Body>,
+        ip: IpAddr,
+    ) -> Result<Response<Body>, error::Error> {
+        let path = request.uri().path();
+
+        if path == VERSIONS_PATH {
+            let versions = Versions {
+                versions: PROTOCOL_VERSIONS.to_vec(),
+            };
+
+            return Ok(Response::builder()
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&versions).expect("Failed to serialize to JSON"),
+                ))
+                .unwrap());
+        }
+
+        let endpoint = match Endpoint::parse(path) {
+            Some((version, endpoint)) if PROTOCOL_VERSIONS.contains(&version) => endpoint,
+            _ => return Ok(not_found_response()),
+        };
+
+        if *request.method() != endpoint.method() {
+            return Ok(method_not_allowed_response());
+        }
+
+        self.handle_endpoint(endpoint, request, ip).await
+    }
+
+    /// Handle an authenticated request from a client which predates the control endpoints
+    async fn handle_proxy_client_request(
+        &self,
+        request: Request<Body>,
+        ip: IpAddr,
+    ) -> Result<Response<Body>, error::Error> {
+        let endpoint = match *request.method() {
+            Method::GET => Endpoint::Poll,
+            Method::POST => Endpoint::Respond,
+            Method::DELETE => Endpoint::Disconnect,
+            _ => return Ok(method_not_allowed_response()),
+        };
+
+        self.handle_endpoint(endpoint, request, ip).await
+    }
+
+    async fn handle_endpoint(
+        &self,
+        endpoint: Endpoint,
+        request: Request<Body>,
+        ip: IpAddr,
+    ) -> Result<Response<Body>, error::Error> {
+        let client_id = client_id(&request);
+
+        if let (Some(id), false) = (client_id, endpoint == Endpoint::Disconnect) {
+            if self
+                .clients
+                .lock()
+                .await
+                .insert(id, Instant::now())
+                .is_none()
+            {
+                self.audit_log
+                    .record(ip, AuthEvent::ClientConnected(client_id));
+            }
+        }
+
+        if matches!(endpoint, Endpoint::Poll | Endpoint::Respond) {
+            if let Some(response) = self.check_required_capabilities(client_id).await {
+                return Ok(response);
+            }
+        }
+
+        match endpoint {
+            Endpoint::Hello => self.hello(request, client_id).await,
+            Endpoint::Poll => self.pop_request(client_id).await,
+            Endpoint::Respond => self.push_response(request).await,
+            Endpoint::StreamPoll => self.pop_stream_events(client_id).await,
+            Endpoint::StreamSend => self.push_stream_events(request, client_id).await,
+            Endpoint::Disconnect => {
+                self.audit_log
+                    .record(ip, AuthEvent::ClientDisconnected(client_id));
+                self.disconnect_client(client_id).await
+            }
+        }
+    }
+
+    /// Push the request to the queue for the client, unless the visitor is being too pushy
+    /// or isn't allowed through.
+    async fn handle_visitor_request(
+        &self,
+        req: Request<Body>,
+        visitor: IpAddr,
+    ) -> Result<Response<Body>, error::Error> {
+        if !self.config.access_list.is_allowed(visitor) {
+            println!("Rejecting request from {}, which isn't allowed", visitor);
+            return Ok(forbidden_response());
+        }
+
+        if let Some(response) = self.check_rate_limits(visitor).await {
+            return Ok(response);
+        }
+
+        // Webhook providers can't log in, but their signature vouches for them instead. It's
+        // checked before the request is queued, so nothing is answered for them until then.
+        if self.is_webhook_path(req.uri().path()) {
+            return self.push_request(req, visitor).await;
+        }
+
+        // Public mock routes, such as robots.txt, don't need visitor credentials
+        if let Some(response) = public_mock_response(&self.config.mock_routes, req.uri().path()) {
+            return Ok(response);
+        }
+
+        let req = match &self.visitor_auth {
+            Some(auth) => {
+                let is_login = auth.is_login_request(&req);
+                let presented_credentials = is_login || auth.presents_credentials(&req);
+
+                if presented_credentials {
+                    if let Some(response) = self.check_lockout(visitor, Credentials::Visitor).await
+                    {
+                        return Ok(response);
+                    }
+                }
+
+                if is_login {
+                    let secure = self.visitor_scheme(req.headers()) == "https";
+                    let form = read_body_limited(req.into_body(), MAX_LOGIN_FORM_SIZE)
+                        .await?
+                        .unwrap_or_default();
+
+                    let response = auth.login(form, secure).await;
+
+                    if response.status() == StatusCode::SEE_OTHER {
+                        self.record_auth_success(visitor, Credentials::Visitor)
+                            .await;
+                        self.audit_log.record(visitor, AuthEvent::VisitorLoggedIn);
+                    } else {
+                        self.record_auth_failure(
+                            visitor,
+                            Credentials::Visitor,
+                            AuthEvent::VisitorRejected,
+                        )
+                        .await;
+                    }
+
+                    return Ok(response);
+                }
+
+                match auth.check(req).await {
+                    Ok(req) => {
+                        self.record_auth_success(visitor, Credentials::Visitor)
+                            .await;
+                        req
+                    }
+                    Err(response) if presented_credentials => {
+                        println!("Rejecting visitor {} with invalid credentials", visitor);
+                        self.record_auth_failure(
+                            visitor,
+                            Credentials::Visitor,
+                            AuthEvent::VisitorRejected,
+                        )
+                        .await;
+                        return Ok(response);
+                    }
+                    Err(response) => {
+                        println!("Rejecting unauthenticated visitor {}", visitor);
+                        return Ok(response);
+                    }
+                }
+            }
+            None => req,
+        };
+
+        // Answered by the server itself, whether or not the client is there to do it
+        if let Some(response) = mock_response(&self.config.mock_routes, req.uri().path()) {
+            return Ok(response);
+        }
+
+        self.push_request(req, visitor).await
+    }
+
+    /// Describe the state of the tunnel for health checks and load balancers.
+    ///
+    /// The server is healthy as long as it's answering at all, but only ready while it's
+    /// accepting requests and a client is connected to answer them.
+    async fn probe_response(&self, path: &str) -> Response<Body> {
+        let clients = self.connected_clients().await;
+        let draining = self.draining.load(Ordering::SeqCst);
+        let ready = clients > 0 && !draining;
+
+        let mut state = json!({
+            "status": if draining { "draining" } else { "ok" },
+            "ready": ready,
+            "uptime": self.started.elapsed().as_secs(),
+            "clients": clients,
+            "queued": self.requests.lock().await.len(),
+            "in_flight": self.in_flight.lock().await.len(),
+        });
+
+        if let Some(capture) = &self.capture {
+            state["captured"] = json!(capture.lock().await.len());
+        }
+
+        let status = if path == READY_PATH && !ready {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        };
+
+        Response::builder()
+            .status(status)
+            .header("content-type", "application/json")
+            .header("cache-control", "no-store")
+            .body(Body::from(state.to_string()))
+            .unwrap()
+    }
+
+    /// Failed authentication attempts with the given kind of credentials, if tracked
+    fn auth_failures(
+        &self,
+        credentials: Credentials,
+    ) -> Option<&Arc<Mutex<FailureTracker<IpAddr>>>> {
+        match credentials {
+            Credentials::Client => self.client_auth_failures.as_ref(),
+            Credentials::Visitor => self.visitor_auth_failures.as_ref(),
+        }
+    }
+
+    /// Returns the response to send if `ip` is locked out for failing to authenticate too often
+    async fn check_lockout(&self, ip: IpAddr, credentials: Credentials) -> Option<Response<Body>> {
+        let failures = self.auth_failures(credentials)?;
+
+        match failures.lock().await.check(&ip, Instant::now()) {
+            Ok(()) => None,
+            Err(retry_after) => {
+                self.audit_log.record(ip, AuthEvent::LockoutRefused);
+                Some(locked_out_response(retry_after))
+            }
+        }
+    }
+
+    async fn record_auth_failure(&self, ip: IpAddr, credentials: Credentials, event: AuthEvent) {
+        self.audit_log.record(ip, event);
+
+        if let Some(failures) = self.auth_failures(credentials) {
+            let locked_out = failures.lock().await.record_failure(ip, Instant::now());
+
+            if let Some(duration) = locked_out {
+                println!("Locking out {} for {}s", ip, duration.as_secs());
+                self.audit_log.record(ip, AuthEvent::LockedOut(duration));
+            }
+        }
+    }
+
+    async fn record_auth_success(&self, ip: IpAddr, credentials: Credentials) {
+        if let Some(failures) = self.auth_failures(credentials) {
+            failures.lock().await.record_success(&ip);
+        }
+    }
+
+    /// Take a token from the visitor's and the tunnel's rate limiters.
+    ///
+    /// Returns the response to send instead if either of them is exhausted, in which case
+    /// neither is charged for the request.
+    async fn check_rate_limits(&self, visitor: IpAddr) -> Option<Response<Body>> {
+        let now = Instant::now();
+
+        let mut ip_limiter = match &self.ip_rate_limiter {
+            Some(limiter) => Some(limiter.lock().await),
+            None => None,
+        };
+        let mut tunnel_limiter = match &self.tunnel_rate_limiter {
+            Some(limiter) => Some(limiter.lock().await),
+            None => None,
+        };
+
+        if let Some(limiter) = &mut ip_limiter {
+            if let Err(limited) = limiter.peek(visitor, now) {
+                println!("Rate limiting visitor {}", visitor);
+                return Some(rate_limited_response(limiter.limit(), &limited));
+            }
+        }
+
+        if let Some(limiter) = &mut tunnel_limiter {
+            if let Err(limited) = limiter.peek((), now) {
+                println!("Rate limiting tunnel; rejecting request from {}", visitor);
+                return Some(rate_limited_response(limiter.limit(), &limited));
+            }
+        }
+
+        // Both have a token to spare, and nobody else can take it while they're locked
+        if let Some(limiter) = &mut ip_limiter {
+            let _ = limiter.check(visitor, now);
+        }
+        if let Some(limiter) = &mut tunnel_limiter {
+            let _ = limiter.check((), now);
+        }
+
+        None
+    }
+
+    /// The scheme the visitor used to reach us. We only ever speak plain HTTP ourselves, but
+    /// a trusted reverse proxy in front of us may have terminated TLS.
+    fn visitor_scheme(&self, headers: &HeaderMap) -> String {
+        let forwarded_proto = headers
+            .get("x-forwarded-proto")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|proto| proto == "http" || proto == "https");
+
+        match (&self.config.trusted_ip_header, forwarded_proto) {
+            (TrustedIpHeader::None, _) | (_, None) => String::from("http"),
+            (_, Some(proto)) => proto,
+        }
+    }
+
+    /// Check the signature of requests to webhook paths, if webhook verification is configured
+    fn verify_webhook(&self, req: &Request<Bytes>) -> Result<(), WebhookError> {
+        let webhook = match &self.config.webhook {
+            Some(webhook) if self.is_webhook_path(req.uri().path()) => webhook,
+            _ => return Ok(()),
+        };
+
+        webhook
+            .verifier
+            .verify(req.headers(), req.body(), SystemTime::now())
+    }
+
+    /// Whether requests to `path` must carry a valid webhook signature
+    fn is_webhook_path(&self, path: &str) -> bool {
+        self.config.webhook.as_ref().is_some_and(|webhook| {
+            webhook.paths.is_empty() || webhook.paths.iter().any(|p| path.starts_with(p.as_str()))
+        })
+    }
+
+    async fn push_request(
+        &self,
+        req: Request<Body>,
+        visitor: IpAddr,
+    ) -> Result<Response<Body>, error::Error> {
+        println!("{}", &req.uri());
+
+        if self.draining.load(Ordering::SeqCst) {
+            return Ok(shutting_down_response());
+        }
+
+        let limits = &self.config.limits;
+
+        // Turn away anything that's obviously too large before reading it
+        let content_length = req
+            .headers()
+            .get("content-length")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| usize::from_str(h).ok());
+
+        if content_length.is_some_and(|length| length > limits.max_body_size) {
+            return Ok(payload_too_large_response());
+        }
+
+        let (mut parts, body) = req.into_parts();
+
+        // Remember who sent the request, and how, for the internal service
+        parts.extensions.insert(Origin {
+            addr: visitor,
+            scheme: self.visitor_scheme(&parts.headers),
+            host: parts
+                .headers
+                .get("host")
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string()),
+        });
+
+        // Only the visitor's connection to us is described by these, and the tunnel's own
+        // headers must never make it to the internal service.
+        strip_hop_by_hop(&mut parts.headers);
+        strip_control_headers(&mut parts.headers);
+
+        let req = match read_body_limited(body, limits.max_body_size).await? {
+            Some(bytes) => Request::from_parts(parts, bytes),
+            None => return Ok(payload_too_large_response()),
+        };
+
+        if let Err(e) = self.verify_webhook(&req) {
+            println!("Rejecting webhook to {}: {}", req.uri().path(), e);
+            return Ok(invalid_webhook_response());
+        }
+
+        let request_id = Uuid::new_v4();
+
+        if let Some(response) = self.capture_request(request_id, &req).await {
+            return Ok(response);
+        }
+
+        // Nobody would pick the request up, so don't keep the visitor waiting
+        if let Some(offline) = &self.config.offline_response {
+            if self.connected_clients().await == 0 {
+                return Ok(offline.respond(req.uri().path()));
+            }
+        }
+
+        {
+            let mut requests = self.requests.lock().await;
+
+            if requests.len() >= limits.max_queued_requests {
+                println!("Request queue is full; rejecting request");
+                return Ok(server_busy_response(
+                    "🚦 Too many requests are waiting; try again later",
+                ));
+            }
+
+            let (visitor, priority) = self.queue_position(&req);
+            requests.push_back(request_id, visitor, priority, req);
+        }
+
+        let await_response = ProxiedResponse {
+            request_id,
+            responses: self.responses.clone(),
+        };
+
+        let timeout_response: Response<Body> = Response::builder()
+            .status(504)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(Body::from("😶 Timeout".to_string()))
+            .unwrap();
+
+        match timeout(RESPONSE_TIMEOUT, await_response).await {
+            Ok(r) => Ok(r),
+            Err(_) => {
+                self.remove_request(request_id).await;
+                self.in_flight
+                    .lock()
+                    .await
+                    .expire(request_id, Instant::now());
+
+                // A response may have slipped in just as we stopped waiting for it
+                self.responses.lock().await.remove(&request_id);
+
+                Ok(timeout_response)
+            }
+        }
+    }
+
+    /// Remove a request from the request queue
+    async fn remove_request(&self, request_id: Uuid) {
+        self.requests.lock().await.remove(request_id);
+    }
+
+    /// Where a request belongs in the queue: who sent it, and how urgent it is
+    fn queue_position(&self, req: &Request<Bytes>) -> (IpAddr, Priority) {
+        let visitor = req
+            .extensions()
+            .get::<Origin>()
+            .map_or(IpAddr::from([0, 0, 0, 0]), |origin| origin.addr);
+
+        let priority = self
+            .config
+            .priority_rules
+            .priority(req.uri().path(), req.headers());
+
+        (visitor, priority)
+    }
+
+    /// Put requests taken back from a client at the front of the queue, since they've already
+    /// waited the longest
+    async fn requeue(&self, reclaimed: Vec<(Uuid, Request<Bytes>)>, reason: &str) {
+        if reclaimed.is_empty() {
+            return;
+        }
+
+        let mut requests = self.requests.lock().await;
+
+        for (request_id, req) in reclaimed {
+            println!("Re-queueing request {}: {}", request_id, reason);
+            let (visitor, priority) = self.queue_position(&req);
+            requests.push_front(request_id, visitor, priority, req);
+        }
+    }
+
+    /// Pop a queued request, if any, and return the serialized request
+    async fn pop_request(&self, client_id: Option<Uuid>) -> Result<Response<Body>, error::Error> {
+        let reclaimed = self
+            .in_flight
+            .lock()
+            .await
+            .reclaim_expired_leases(Instant::now());
+        self.requeue(reclaimed, "the client's lease expired").await;
+
+        // Don't hand out any new work while shutting down
+        let req = if self.draining.load(Ordering::SeqCst) {
+            None
+        } else {
+            self.requests
+                .try_lock()
+                .map(|mut r| r.pop_front())
+                .unwrap_or(None)
+        };
+
+        let format = self.wire_format(client_id).await;
+
+        let (req_id, req) = match req {
+            Some(req) => req,
+
+            // Visitors waiting on a live response go first; captured requests can wait
+            None => {
+                return Ok(match self.next_captured_request().await {
+                    Some(captured) => {
+                        // Captured requests are stored as JSON, which every client understands
+                        let transcoded = match format {
+                            WireFormat::Json => None,
+                            format => serde_json::from_str::<ProxiedRequest>(&captured)
+                                .map(|request| format.encode(&request))
+                                .ok(),
+                        };
+
+                        match transcoded {
+                            Some(message) => self.control_message(client_id, format, message),
+                            None => self.control_message(
+                                client_id,
+                                WireFormat::Json,
+                                captured.into_bytes(),
+                            ),
+                        }
+                        .await
+                    }
+                    None => Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap(),
+                });
+            }
+        };
+
+        // Only requests which are safe to send twice may be handed to another client
+        let retained = is_idempotent(req.method()).then(|| copy_request(&req));
+        self.in_flight
+            .lock()
+            .await
+            .dispatch(req_id, client_id, retained, Instant::now());
+
+        let message = format.encode(&proxied_request(req_id, &req));
+        Ok(self.control_message(client_id, format, message).await)
+    }
+
+    /// The next captured request for a client to answer, if capturing is enabled
+    async fn next_captured_request(&self) -> Option<String> {
+        if self.draining.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let next = self
+            .with_capture(move |capture| capture.next_delivery(now))
+            .await?;
+
+        match next {
+            Ok(next) => next.map(|(id, request)| {
+                println!("Delivering captured request {}", id);
+                request
+            }),
+            Err(e) => {
+                eprintln!("ERROR: Failed to read captured request! {}", e);
+                None
+            }
+        }
+    }
+
+    /// Agree on the capabilities to use with a client, or refuse it if it lacks any we require
+    async fn hello(
+        &self,
+        request: Request<Body>,
+        client_id: Option<Uuid>,
+    ) -> Result<Response<Body>, error::Error> {
+        let bytes = body::to_bytes(request.into_body())
+            .await
+            .map_err(error::Error::from)?;
+
+        let hello = match serde_json::from_slice::<Hello>(&bytes) {
+            Ok(hello) => hello,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(Body::from(
+                        "🤢 Your request was bad and you should feel bad",
+                    ))
+                    .unwrap());
+            }
+        };
+
+        if !PROTOCOL_VERSIONS.contains(&hello.version) {
+            return Ok(incompatible_client_response(format!(
+                "This server speaks protocol versions {:?}, not {}",
+                PROTOCOL_VERSIONS, hello.version
+            )));
+        }
+
+        let missing: Vec<String> = self
+            .config
+            .required_capabilities
+            .iter()
+            .filter(|capability| !hello.capabilities.contains(capability))
+            .map(|capability| capability.to_string())
+            .collect();
+
+        if !missing.is_empty() {
+            println!(
+                "Refusing client {}, which doesn't support {}",
+                client_id.map_or("[anonymous]".to_string(), |id| id.to_string()),
+                missing.join(", ")
+            );
+
+            return Ok(incompatible_client_response(format!(
+                "This server requires clients to support {}",
+                missing.join(", ")
+            )));
+        }
+
+        let agreed = agree(&CAPABILITIES, &hello.capabilities);
+
+        if let Some(id) = client_id {
+            println!(
+                "Client {} speaks protocol version {} with capabilities [{}]",
+                id,
+                hello.version,
+                agreed
+                    .iter()
+                    .map(|capability| capability.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+
+            self.sessions.lock().await.insert(id, agreed.clone());
+        }
+
+        let answer = Hello {
+            version: hello.version,
+            capabilities: agreed,
+        };
+
+        Ok(Response::builder()
+            .header("content-type", "application/json")
+            .body(Body::from(
+                serde_json::to_string(&answer).expect("Failed to serialize to JSON"),
+            ))
+            .unwrap())
+    }
+
+    /// Whether the client agreed to a capability in its hello
+    async fn client_supports(&self, client_id: Option<Uuid>, capability: Capability) -> bool {
+        let Some(id) = client_id else {
+            return false;
+        };
+
+        self.sessions
+            .lock()
+            .await
+            .get(&id)
+            .is_some_and(|agreed| agreed.contains(&capability))
+    }
+
+    /// Refuse clients which haven't agreed to every required capability
+    async fn check_required_capabilities(&self, client_id: Option<Uuid>) -> Option<Response<Body>> {
+        for capability in &self.config.required_capabilities {
+            if !self.client_supports(client_id, *capability).await {
+                return Some(incompatible_client_response(format!(
+                    "This server requires clients to say hello and support {}",
+                    capability
+                )));
+            }
+        }
+
+        None
+    }
+
+    /// How to encode requests for a client, given what it agreed to
+    async fn wire_format(&self, client_id: Option<Uuid>) -> WireFormat {
+        let sessions = self.sessions.lock().await;
+
+        match client_id.and_then(|id| sessions.get(&id)) {
+            Some(agreed) => WireFormat::agreed(agreed),
+            None => WireFormat::Json,
+        }
+    }
+
+    /// A message for a client, compressed if it agreed to that
+    async fn control_message(
+        &self,
+        client_id: Option<Uuid>,
+        format: WireFormat,
+        message: Vec<u8>,
+    ) -> Response<Body> {
+        let (body, encoding) = if self
+            .client_supports(client_id, Capability::Compression)
+            .await
+        {
+            compress(message)
+        } else {
+            (message, None)
+        };
+
+        let mut response = Response::builder().header("content-type", format.content_type());
+        if let Some(encoding) = encoding {
+            response = response.header("content-encoding", encoding);
+        }
+
+        response.body(Body::from(body)).unwrap()
+    }
+
+    /// Number of identified clients which have polled recently
+    async fn connected_clients(&self) -> usize {
+        self.clients
+            .lock()
+            .await
+            .values()
+            .filter(|last_seen| last_seen.elapsed() < CLIENT_TIMEOUT)
+            .count()
+    }
+
+    /// Use the capture store, if capturing is enabled, on a thread where it may block on disk
+    /// without holding up other requests
+    async fn with_capture<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut CaptureStore) -> R + Send + 'static,
+    ) -> Option<R> {
+        let capture = self.capture.clone()?;
+
+        let result = tokio::task::spawn_blocking(move || f(&mut capture.blocking_lock()))
+            .await
+            .expect("Failed to use the capture store");
+
+        Some(result)
+    }
+
+    /// Acknowledge the request on the client's behalf and store it for the client, if it's
+    /// one that should be captured
+    async fn capture_request(
+        &self,
+        request_id: Uuid,
+        req: &Request<Bytes>,
+    ) -> Option<Response<Body>> {
+        let config = self.config.capture.as_ref()?;
+        self.capture.as_ref()?;
+
+        let path = req.uri().path();
+        if !config.paths.is_empty() && !config.paths.iter().any(|p| path.starts_with(p.as_str())) {
+            return None;
+        }
+
+        if config.only_offline && self.connected_clients().await > 0 {
+            return None;
+        }
+
+        let serialized = serde_json::to_string(&proxied_request(request_id, req))
+            .expect("Failed to serialize to JSON");
+        let max_requests = config.max_requests;
+
+        let captured = self
+            .with_capture(move |capture| {
+                if capture.len() >= max_requests {
+                    return None;
+                }
+
+                Some(capture.capture(request_id, &serialized))
+            })
+            .await?;
+
+        match captured {
+            None => {
+                println!("Capture store is full; rejecting request");
+                return Some(server_busy_response(
+                    "🚦 Too many requests are waiting; try again later",
+                ));
+            }
+            Some(Err(e)) => {
+                eprintln!("ERROR: Failed to capture request {}! {}", request_id, e);
+                return Some(server_busy_response("💾 The request couldn't be stored"));
+            }
+            Some(Ok(())) => {}
+        }
+
+        println!("Captured request {} to {}", request_id, path);
+
+        Some(
+            Response::builder()
+                .status(config.status)
+                .header("content-type", config.content_type.as_str())
+                .body(Body::from(config.body.clone()))
+                .unwrap(),
+        )
+    }
+
+    /// Stop accepting visitor requests, and answer everything that's still waiting.
+    ///
+    /// Queued requests are failed immediately. Requests which were already handed to a client
+    /// are given until `grace_period` elapses for their response to arrive, after which they
+    /// are failed as well.
+    async fn drain(&self, grace_period: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+
+        let queued: Vec<Uuid> = {
+            let mut requests = self.requests.lock().await;
+            requests.drain()
+        };
+
+        if !queued.is_empty() {
+            println!("Rejecting {} queued request(s)", queued.len());
+            self.fail_requests(queued, shutting_down_response).await;
+        }
+
+        let deadline = Instant::now() + grace_period;
+        loop {
+            let remaining = self.in_flight.lock().await.len();
+
+            if remaining == 0 {
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                println!("Gave up waiting on {} in-flight request(s)", remaining);
+                let in_flight = self.in_flight.lock().await.expire_all(Instant::now());
+                self.fail_requests(in_flight, shutting_down_response).await;
+                break;
+            }
+
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Answer each of the given requests with the response built by `response`
+    async fn fail_requests(&self, request_ids: Vec<Uuid>, response: fn() -> Response<Body>) {
+        // Some of these may have been handed out before, and no answer is wanted for them now
+        {
+            let mut in_flight = self.in_flight.lock().await;
+            let now = Instant::now();
+
+            for id in &request_ids {
+                in_flight.expire(*id, now);
+            }
+        }
+
+        let mut responses = self.responses.lock().await;
+
+        for id in request_ids {
+            responses.insert(id, response());
+        }
+    }
+
+    /// Handle a client announcing that it's going away.
+    ///
+    /// Anything that was handed to the client, but never answered, is failed. If no other
+    /// client is connected, the queued requests are failed too rather than left to time out.
+    async fn disconnect_client(
+        &self,
+        client_id: Option<Uuid>,
+    ) -> Result<Response<Body>, error::Error> {
+        let remaining_clients = {
+            let mut clients = self.clients.lock().await;
+
+            if let Some(id) = client_id {
+                clients.remove(&id);
+                self.sessions.lock().await.remove(&id);
+            }
+
+            clients
+                .values()
+                .filter(|last_seen| last_seen.elapsed() < CLIENT_TIMEOUT)
+                .count()
+        };
+
+        println!(
+            "Client {} disconnected; {} client(s) remaining",
+            client_id.map_or("[anonymous]".to_string(), |id| id.to_string()),
+            remaining_clients
+        );
+
+        if let (Some(streams), Some(id)) = (&self.streams, client_id) {
+            streams.release_client(id).await;
+        }
+
+        if let Some(id) = client_id {
+            let (reclaimed, abandoned) = self
+                .in_flight
+                .lock()
+                .await
+                .release_client(id, Instant::now());
+
+            self.requeue(reclaimed, "the client disconnected").await;
+
+            self.fail_requests(abandoned, client_disconnected_response)
+                .await;
+        }
+
+        if remaining_clients == 0 {
+            let queued: Vec<Uuid> = {
+                let mut requests = self.requests.lock().await;
+                requests.drain()
+            };
+
+            self.fail_requests(queued, client_disconnected_response)
+                .await;
+        }
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    /// Hand a client what happened to visitors' TCP connections, waiting a while if nothing has
+    async fn pop_stream_events(
+        &self,
+        client_id: Option<Uuid>,
+    ) -> Result<Response<Body>, error::Error> {
+        let Some(streams) = &self.streams else {
+            return Ok(not_found_response());
+        };
+        let Some(client_id) = client_id else {
+            return Ok(anonymous_stream_client_response());
+        };
+
+        let events = streams.take(client_id, STREAM_POLL_WAIT).await;
+        if events.is_empty() {
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        let message = serde_json::to_vec(&events).expect("Failed to serialize to JSON");
+        Ok(self
+            .control_message(Some(client_id), WireFormat::Json, message)
+            .await)
+    }
+
+    /// Pass on what a client said happened to its ends of visitors' TCP connections
+    async fn push_stream_events(
+        &self,
+        request: Request<Body>,
+        client_id: Option<Uuid>,
+    ) -> Result<Response<Body>, error::Error> {
+        let Some(streams) = &self.streams else {
+            return Ok(not_found_response());
+        };
+        let Some(client_id) = client_id else {
+            return Ok(anonymous_stream_client_response());
+        };
+
+        let encoding = request
+            .headers()
+            .get("content-encoding")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+
+        let bytes = body::to_bytes(request.into_body())
+            .await
+            .map_err(error::Error::from)?
+            .to_vec();
+
+        let events = match decompress(bytes, encoding.as_deref(), MAX_DECOMPRESSED_MESSAGE)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                serde_json::from_slice::<Vec<StreamEvent>>(&bytes).map_err(|e| e.to_string())
+            }) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Failed to read stream events from a client: {}", e);
+
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(Body::from(
+                        "🤢 Your request was bad and you should feel bad",
+                    ))
+                    .unwrap());
+            }
+        };
+
+        streams.receive(client_id, events).await;
+
+        Ok(Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap())
+    }
+
+    /// Carry a visitor's TCP connection through the tunnel, unless they aren't allowed through
+    async fn carry_connection(&self, socket: TcpStream, peer: SocketAddr) {
+        let Some(streams) = &self.streams else {
+            return;
+        };
+
+        // Nothing in front of the server can vouch for a raw connection's real address
+        let visitor = peer.ip();
+
+        if !self.config.access_list.is_allowed(visitor) {
+            println!(
+                "Rejecting TCP connection from {}, which isn't allowed",
+                visitor
+            );
+            return;
+        }
+
+        if self.draining.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if self.check_rate_limits(visitor).await.is_some() {
+            println!(
+                "Rejecting TCP connection from {}, which is rate limited",
+                visitor
+            );
+            return;
+        }
+
+        if !streams.has_client(CLIENT_TIMEOUT).await {
+            println!(
+                "Rejecting TCP connection from {}, since no client is connected",
+                visitor
+            );
+            return;
+        }
+
+        let id = Uuid::new_v4();
+        println!("TCP connection {} opened by {}", id, visitor);
+
+        let from_client = streams.open(id, visitor).await;
+        relay(streams, id, socket, from_client).await;
+
+        println!("TCP connection {} closed", id);
+    }
+
+    async fn push_response(&self, request: Request<Body>) -> Result<Response<Body>, error::Error> {
+        // println!("Received client POST response");
+
+        let encoding = request
+            .headers()
+            .get("content-encoding")
+            .and_then(|h| h.to_str().ok())
+            .map(|h| h.to_string());
+        let format = WireFormat::from_content_type(
+            request
+                .headers()
+                .get("content-type")
+                .and_then(|h| h.to_str().ok()),
+        );
+
+        let bytes = body::to_bytes(request.into_body())
+            .await
+            .map_err(error::Error::from)?
+            .to_vec();
+
+        let bytes = match decompress(bytes, encoding.as_deref(), MAX_DECOMPRESSED_MESSAGE) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Failed to decompress a client response: {}", e);
+
+                return Ok(Response::builder()
+                    .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(Body::from(format!(
+                        "🗜 Couldn't decompress the response: {}",
+                        e
+                    )))
+                    .unwrap());
+            }
+        };
+
+        let client_response = match format.decode::<ClientResponse>(&bytes) {
+            Ok(r) => r,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(Body::from(
+                        "🤢 Your request was bad and you should feel bad",
+                    ))
+                    .unwrap());
+            }
+        };
+
+        let request_id = client_response.request_id;
+
+        // Nobody is waiting on the answer to a captured request; it's only kept for inspection
+        let is_captured = match &self.capture {
+            Some(capture) => capture.lock().await.is_pending(request_id),
+            None => false,
+        };
+
+        if is_captured {
+            let recorded = self
+                .with_capture(move |capture| {
+                    // Another client may have answered it in the meantime
+                    if !capture.is_pending(request_id) {
+                        return Ok(false);
+                    }
+
+                    capture.record_response(&client_response).map(|()| true)
+                })
+                .await;
+
+            return Ok(match recorded {
+                Some(Ok(true)) => {
+                    println!("Recorded response to captured request {}", request_id);
+                    Response::builder()
+                        .body(Body::from(request_id.hyphenated().to_string()))
+                        .unwrap()
+                }
+                Some(Ok(false)) | None => rejected_response_response(Rejection::AlreadyAnswered),
+                Some(Err(e)) => {
+                    eprintln!("ERROR: Failed to record response to {}! {}", request_id, e);
+                    server_busy_response("💾 The response couldn't be stored")
+                }
+            });
+        }
+
+        let status = client_response.status_code();
+
+        let mut headers = client_response.headers();
+        strip_hop_by_hop(&mut headers);
+        strip_control_headers(&mut headers);
+
+        // Describe the body the visitor actually receives. An empty body keeps whatever length
+        // the client reported, since that's how HEAD and 304 responses declare their size.
+        let body = client_response.body.0;
+        if !body.is_empty() {
+            headers.insert("content-length", HeaderValue::from(body.len()));
+        }
+
+        let mut response = Response::builder()
+            .status(status)
+            .body(Body::from(body))
+            .unwrap(); // TODO: Remove unwrap call
+
+        *response.headers_mut() = headers;
+
+        {
+            let mut responses = self.responses.lock().await;
+
+            if responses.len() >= self.config.limits.max_pending_responses {
+                println!("Too many uncollected responses; rejecting response");
+                return Ok(server_busy_response(
+                    "🚦 Too many responses are waiting to be collected",
+                ));
+            }
+
+            // Only a request somebody is still waiting on can be answered, and only once
+            let accepted = self
+                .in_flight
+                .lock()
+                .await
+                .answer(request_id, Instant::now());
+
+            if let Err(rejection) = accepted {
+                println!("Rejecting response to {}: {}", request_id, rejection);
+                return Ok(rejected_response_response(rejection));
+            }
+
+            responses.insert(request_id, response);
+        }
+
+        // The request may have been taken back and queued for another client in the meantime
+        self.remove_request(request_id).await;
+
+        // Update so that the ProxiedResponse future can continue
+
+        Ok(Response::builder()
+            .body(Body::from(request_id.hyphenated().to_string()))
+            .unwrap())
+    }
+}
+
+/// Serve the given kind of traffic on `addr` until `stopped` completes
+async fn serve(
+    proxy: RequestProxy,
+    addr: SocketAddr,
+    listener: Listener,
+    stopped: impl Future<Output = ()>,
+) {
+    let make_svc = make_service_fn(|conn: &AddrStream| {
+        let proxy_clone = proxy.clone();
+        let remote_addr = conn.remote_addr();
+
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                let proxy_clone2 = proxy_clone.clone();
+                async move {
+                    proxy_clone2
+                        .call(request, remote_addr, listener)
+                        .map_err(move |e| e.compat())
+                        .await
+                }
+            }))
+        }
+    });
+
+    let server = Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(stopped);
+
+    match listener {
+        Listener::Control => println!("Listening for clients on http://{}", addr),
+        _ => println!("Listening on http://{}", addr),
+    }
+
+    if let Err(err) = server.await {
+        eprintln!("server error: {}", err);
+    }
+}
+
+/// Accept visitors' TCP connections on `addr` and carry them to clients, until `stopped`
+/// completes
+async fn serve_tcp(proxy: RequestProxy, addr: SocketAddr, stopped: impl Future<Output = ()>) {
+    let listener = TcpListener::bind(addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to listen on $TCP_PORT! {}", e));
+
+    println!("Listening for TCP connections on {}", addr);
+
+    tokio::pin!(stopped);
+    loop {
+        tokio::select! {
+            _ = &mut stopped => break,
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    let proxy = proxy.clone();
+                    tokio::spawn(async move { proxy.carry_connection(socket, peer).await });
+                }
+                Err(e) => eprintln!("Failed to accept a TCP connection: {}", e),
+            },
+        }
+    }
+}
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
+async fn main() {
+    dotenv().ok();
+
+    let config = Config::from_env();
+
+    if config.secret_generated {
+        // A generated key is useless unless someone can read it, but it's only ever shown
+        // on an interactive terminal so that it doesn't end up in collected logs.
+        if io::stdout().is_terminal() {
+            println!(
+                "$PROXY_SECRET isn't set; using '{}' as a one-time proxy secret key.",
+                config.secret
+            );
+        } else {
+            println!("$PROXY_SECRET isn't set; clients won't be able to connect!");
+        }
+    }
+
+    let audit_log = AuditLog::open(config.audit_log.as_deref())
+        .unwrap_or_else(|e| panic!("Failed to open $AUTH_AUDIT_LOG! {}", e));
+
+    let capture = config.capture.as_ref().map(|capture| {
+        let store = CaptureStore::open(&capture.dir, CAPTURE_REDELIVERY)
+            .unwrap_or_else(|e| panic!("Failed to open $CAPTURE_DIR! {}", e));
+
+        if !store.is_empty() {
+            println!("{} captured request(s) waiting for a client", store.len());
+        }
+
+        Arc::new(Mutex::new(store))
+    });
+
+    let listen_addr = config.listen_addr;
+    let shutdown_timeout = config.shutdown_timeout;
+
+    tokio::spawn(async move {
+        let proxy = RequestProxy::new(config, audit_log, capture);
+
+        let shutdown_proxy = proxy.clone();
+        let control_addr = proxy.config.control_addr;
+
+        // Keep accepting connections while draining, so that clients can still post
+        // their responses; hyper only stops listening once the drain has completed.
+        let stopped = async move {
+            shutdown_signal().await;
+
+            println!(
+                "Shutting down; waiting up to {}s for in-flight requests...",
+                shutdown_timeout.as_secs()
+            );
+            shutdown_proxy.drain(shutdown_timeout).await;
+        }
+        .boxed()
+        .shared();
+
+        let tcp = {
+            let proxy = proxy.clone();
+            let stopped = stopped.clone();
+
+            async move {
+                if let Some(tcp_addr) = proxy.config.tcp_addr {
+                    serve_tcp(proxy, tcp_addr, stopped).await;
+                }
+            }
+        };
+
+        let http = async move {
+            match control_addr {
+                Some(control_addr) => {
+                    tokio::join!(
+                        serve(
+                            proxy.clone(),
+                            listen_addr,
+                            Listener::Public,
+                            stopped.clone()
+                        ),
+                        serve(proxy, control_addr, Listener::Control, stopped),
+                    );
+                }
+                None => serve(proxy, listen_addr, Listener::Combined, stopped).await,
+            }
+        };
+
+        // Run until we're told to stop
+        tokio::join!(http, tcp);
+
+        println!("Shutdown complete");
+    })
+    .await
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use request_proxy::mock::parse_mock_routes;
+    use request_proxy::webhook::WebhookVerifier;
+    use tokio::task::JoinHandle;
+
+    const SECRET: &str = "correct horse battery staple";
+
+    fn proxy() -> RequestProxy {
+        proxy_with(|_| {})
+    }
+
+    fn proxy_with(configure: impl FnOnce(&mut Config)) -> RequestProxy {
+        let mut config = Config::for_tests(SECRET);
+        configure(&mut config);
+
+        RequestProxy::new(config, AuditLog::open(None).unwrap(), None)
+    }
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], 4321))
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    /// Send a visitor's request in the background, since it waits for the client to answer
+    fn visit(proxy: &RequestProxy, request: Request<Body>) -> JoinHandle<Response<Body>> {
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            proxy
+                .call(request, peer(), Listener::Combined)
+                .await
+                .unwrap()
+        })
+    }
+
+    async fn wait_for_queue(proxy: &RequestProxy, length: usize) {
+        timeout(Duration::from_secs(5), async {
+            while proxy.requests.lock().await.len() < length {
+                sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("The request was never queued");
+    }
+
+    fn control_request(endpoint: Endpoint, client_id: Uuid, body: Body) -> Request<Body> {
+        Request::builder()
+            .method(endpoint.method())
+            .uri(endpoint.path(PROTOCOL_VERSIONS[0]))
+            .header("x-proxy-secret", SECRET)
+            .header("x-proxy-client-id", client_id.hyphenated().to_string())
+            .body(body)
+            .unwrap()
+    }
+
+    /// Poll as the given client, returning the ID of the request it was handed, if any
+    async fn poll(proxy: &RequestProxy, client_id: Uuid) -> Option<Uuid> {
+        let response = proxy
+            .call(
+                control_request(Endpoint::Poll, client_id, Body::empty()),
+                peer(),
+                Listener::Combined,
+            )
+            .await
+            .unwrap();
+
+        if response.status() == StatusCode::NO_CONTENT {
+            return None;
+        }
+
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        let request: ProxiedRequest = serde_json::from_slice(&bytes).unwrap();
+        Some(request.id)
+    }
+
+    async fn respond(proxy: &RequestProxy, client_id: Uuid, request_id: Uuid) -> Response<Body> {
+        let response = ClientResponse {
+            request_id,
+            status: 200,
+            headers: vec![],
+            body: Base64Bytes(b"answered".to_vec()),
+        };
+
+        proxy
+            .call(
+                control_request(
+                    Endpoint::Respond,
+                    client_id,
+                    Body::from(serde_json::to_vec(&response).unwrap()),
+                ),
+                peer(),
+                Listener::Combined,
+            )
+            .await
+            .unwrap()
+    }
+
+    async fn disconnect(proxy: &RequestProxy, client_id: Uuid) {
+        let response = proxy
+            .call(
+                control_request(Endpoint::Disconnect, client_id, Body::empty()),
+                peer(),
+                Listener::Combined,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+    }
+
+    async fn text(response: Response<Body>) -> String {
+        let bytes = body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn drain_fails_queued_requests_and_stops_handing_out_work() {
+        let proxy = proxy();
+        let client = Uuid::new_v4();
+
+        let answered = visit(&proxy, get("/answered"));
+        wait_for_queue(&proxy, 1).await;
+        let request_id = poll(&proxy, client).await.unwrap();
+
+        let queued = visit(&proxy, get("/queued"));
+        wait_for_queue(&proxy, 1).await;
+
+        let draining = tokio::spawn({
+            let proxy = proxy.clone();
+            async move { proxy.drain(Duration::from_secs(5)).await }
+        });
+
+        let response = queued.await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(text(response).await.contains("shutting down"));
+
+        // Nobody new gets in, and clients aren't handed anything, even if it's queued again
+        let response = proxy
+            .call(get("/late"), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        proxy
+            .requeue(
+                vec![(Uuid::new_v4(), Request::new(Bytes::new()))],
+                "testing",
+            )
+            .await;
+        assert_eq!(None, poll(&proxy, client).await);
+
+        // What the client already has may still be answered
+        assert_eq!(
+            StatusCode::OK,
+            respond(&proxy, client, request_id).await.status()
+        );
+        assert_eq!("answered", text(answered.await.unwrap()).await);
+
+        timeout(Duration::from_secs(1), draining)
+            .await
+            .expect("The drain should end once nothing is in flight")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_on_requests_the_client_never_answers() {
+        let proxy = proxy();
+        let client = Uuid::new_v4();
+
+        let visitor = visit(&proxy, get("/slow"));
+        wait_for_queue(&proxy, 1).await;
+        let request_id = poll(&proxy, client).await.unwrap();
+
+        let started = Instant::now();
+        proxy.drain(Duration::from_millis(200)).await;
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!(0, proxy.in_flight.lock().await.len());
+
+        let response = visitor.await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        // The visitor has been answered, so a late response has nowhere to go
+        assert_eq!(
+            StatusCode::GONE,
+            respond(&proxy, client, request_id).await.status()
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnecting_client_hands_back_its_requests() {
+        let proxy = proxy();
+        let (leaving, staying) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(None, poll(&proxy, staying).await);
+
+        let upload = visit(
+            &proxy,
+            Request::post("/upload").body(Body::from("data")).unwrap(),
+        );
+        wait_for_queue(&proxy, 1).await;
+        assert!(poll(&proxy, leaving).await.is_some());
+
+        let page = visit(&proxy, get("/page"));
+        wait_for_queue(&proxy, 1).await;
+        let page_id = poll(&proxy, leaving).await.unwrap();
+        assert_eq!(2, proxy.connected_clients().await);
+
+        disconnect(&proxy, leaving).await;
+
+        let clients = proxy.clients.lock().await.clone();
+        assert!(!clients.contains_key(&leaving));
+        assert!(clients.contains_key(&staying));
+
+        // The upload can't safely be sent twice, so its visitor is told what happened...
+        let response = upload.await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(text(response).await.contains("No client"));
+
+        // ...while the page goes to the client that's still around
+        assert_eq!(Some(page_id), poll(&proxy, staying).await);
+        assert_eq!(
+            StatusCode::OK,
+            respond(&proxy, staying, page_id).await.status()
+        );
+        assert_eq!("answered", text(page.await.unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn last_client_disconnecting_fails_queued_requests() {
+        let proxy = proxy();
+        let client = Uuid::new_v4();
+        assert_eq!(None, poll(&proxy, client).await);
+
+        let queued = visit(&proxy, get("/queued"));
+        wait_for_queue(&proxy, 1).await;
+
+        disconnect(&proxy, client).await;
+        assert_eq!(0, proxy.connected_clients().await);
+        assert_eq!(0, proxy.requests.lock().await.len());
+
+        let response = queued.await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(text(response).await.contains("No client"));
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_beyond_the_queue_limit() {
+        let proxy = proxy_with(|config| config.limits.max_queued_requests = 1);
+
+        let _queued = visit(&proxy, get("/first"));
+        wait_for_queue(&proxy, 1).await;
+
+        let response = proxy
+            .call(get("/second"), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert!(response.headers().contains_key("retry-after"));
+        assert_eq!(1, proxy.requests.lock().await.len());
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_beyond_the_size_limit() {
+        let proxy = proxy_with(|config| config.limits.max_body_size = 4);
+
+        // Whether or not the visitor says how large the body is up front
+        let declared = Request::post("/upload")
+            .header("content-length", "10")
+            .body(Body::from("0123456789"))
+            .unwrap();
+        let undeclared = Request::post("/upload")
+            .body(Body::from("0123456789"))
+            .unwrap();
+
+        for request in [declared, undeclared] {
+            let response = proxy
+                .call(request, peer(), Listener::Combined)
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        }
+
+        assert_eq!(0, proxy.requests.lock().await.len());
+    }
+
+    #[tokio::test]
+    async fn response_rejected_for_capacity_can_be_sent_again() {
+        let proxy = proxy_with(|config| config.limits.max_pending_responses = 1);
+        let client = Uuid::new_v4();
+
+        let visitor = visit(&proxy, get("/page"));
+        wait_for_queue(&proxy, 1).await;
+        let request_id = poll(&proxy, client).await.unwrap();
+
+        // Somebody else's response is still waiting to be collected
+        let uncollected = Uuid::new_v4();
+        proxy
+            .responses
+            .lock()
+            .await
+            .insert(uncollected, Response::new(Body::empty()));
+
+        let response = respond(&proxy, client, request_id).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!(1, proxy.in_flight.lock().await.len());
+
+        proxy.responses.lock().await.remove(&uncollected);
+
+        assert_eq!(
+            StatusCode::OK,
+            respond(&proxy, client, request_id).await.status()
+        );
+        assert_eq!("answered", text(visitor.await.unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_use_up_neither_limit() {
+        let proxy = proxy_with(|config| {
+            config.rate_limit_per_ip = Some(RateLimit {
+                rate: 0.001,
+                burst: 2.0,
+            });
+            config.rate_limit_tunnel = Some(RateLimit {
+                rate: 0.001,
+                burst: 1.0,
+            });
+        });
+        let visitor = peer().ip();
+
+        assert!(proxy.check_rate_limits(visitor).await.is_none());
+
+        let response = proxy.check_rate_limits(visitor).await.unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+        assert_eq!("1", response.headers()["x-ratelimit-limit"]);
+        assert_eq!("0", response.headers()["x-ratelimit-remaining"]);
+        assert_eq!("1000", response.headers()["retry-after"]);
+        assert_eq!("1000", response.headers()["x-ratelimit-reset"]);
+
+        // The tunnel turned the request away, so the visitor still has a token left
+        let ip_limiter = proxy.ip_rate_limiter.as_ref().unwrap();
+        assert_eq!(
+            Ok(0),
+            ip_limiter.lock().await.check(visitor, Instant::now())
+        );
+    }
+
+    #[tokio::test]
+    async fn captured_requests_are_stored_and_answered_once() {
+        let dir = std::env::temp_dir().join(format!("request-proxy-server-{}", Uuid::new_v4()));
+
+        let mut config = Config::for_tests(SECRET);
+        config.capture = Some(config::CaptureConfig {
+            dir: dir.clone(),
+            paths: vec![String::from("/hooks")],
+            only_offline: false,
+            status: StatusCode::ACCEPTED,
+            content_type: String::from("text/plain; charset=utf-8"),
+            body: String::from("📥 Accepted"),
+            max_requests: 1,
+        });
+        let store = CaptureStore::open(&dir, CAPTURE_REDELIVERY).unwrap();
+        let proxy = RequestProxy::new(
+            config,
+            AuditLog::open(None).unwrap(),
+            Some(Arc::new(Mutex::new(store))),
+        );
+        let client = Uuid::new_v4();
+
+        let hook = || Request::post("/hooks/push").body(Body::from("{}")).unwrap();
+
+        let response = proxy
+            .call(hook(), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::ACCEPTED, response.status());
+
+        let response = proxy
+            .call(hook(), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        let request_id = poll(&proxy, client).await.unwrap();
+        assert_eq!(
+            StatusCode::OK,
+            respond(&proxy, client, request_id).await.status()
+        );
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            respond(&proxy, client, request_id).await.status()
+        );
+        assert!(dir
+            .join("answered")
+            .join(format!("{}.json", request_id))
+            .exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mock_routes_need_visitor_credentials_unless_public() {
+        let proxy = proxy_with(|config| {
+            config.mock_routes = parse_mock_routes(
+                "/robots.txt text User-agent: *
+                /health public json {\"ok\":true}",
+            )
+            .unwrap();
+            config.visitor_auth.bearer_tokens = vec![String::from("let-me-in")];
+        });
+
+        let response = proxy
+            .call(get("/robots.txt"), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let request = Request::get("/robots.txt")
+            .header("authorization", "Bearer let-me-in")
+            .body(Body::empty())
+            .unwrap();
+        let response = proxy
+            .call(request, peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("User-agent: *", text(response).await);
+
+        let response = proxy
+            .call(get("/health"), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(r#"{"ok":true}"#, text(response).await);
+    }
+
+    #[tokio::test]
+    async fn webhooks_need_a_signature_instead_of_visitor_credentials() {
+        let proxy = proxy_with(|config| {
+            config.visitor_auth.bearer_tokens = vec![String::from("let-me-in")];
+            config.webhook = Some(config::WebhookConfig {
+                verifier: WebhookVerifier::preset("github", "It's a Secret to Everybody".into())
+                    .unwrap(),
+                paths: vec![String::from("/webhooks")],
+            });
+        });
+        let client = Uuid::new_v4();
+        poll(&proxy, client).await;
+
+        // Example from GitHub's documentation
+        let webhook = |signature: &str| {
+            Request::post("/webhooks/github")
+                .header("x-hub-signature-256", signature)
+                .body(Body::from("Hello, World!"))
+                .unwrap()
+        };
+
+        let response = proxy
+            .call(
+                webhook("sha256=0000000000000000000000000000000000000000000000000000000000000000"),
+                peer(),
+                Listener::Combined,
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!("🪝 Invalid webhook signature", text(response).await);
+
+        let delivery = visit(
+            &proxy,
+            webhook("sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"),
+        );
+        wait_for_queue(&proxy, 1).await;
+        let request_id = poll(&proxy, client).await.unwrap();
+        assert_eq!(
+            StatusCode::OK,
+            respond(&proxy, client, request_id).await.status()
+        );
+        assert_eq!("answered", text(delivery.await.unwrap()).await);
+
+        // Everywhere else, visitors still need their credentials
+        let response = proxy
+            .call(get("/"), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[tokio::test]
+    async fn ready_only_while_a_client_can_answer() {
+        let proxy = proxy();
+        let probe = |path| proxy.call(get(path), peer(), Listener::Combined);
+
+        let response = probe(READY_PATH).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        let state: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(false, state["ready"]);
+        assert_eq!(0, state["clients"]);
+
+        // Healthy all the same, since the server itself is fine
+        assert_eq!(StatusCode::OK, probe(HEALTH_PATH).await.unwrap().status());
+
+        assert_eq!(None, poll(&proxy, Uuid::new_v4()).await);
+        let response = probe(READY_PATH).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let state: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(true, state["ready"]);
+        assert_eq!(1, state["clients"]);
+
+        proxy.drain(Duration::ZERO).await;
+        let response = probe(READY_PATH).await.unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        let state: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!("draining", state["status"]);
+    }
+
+    #[tokio::test]
+    async fn probe_paths_are_never_forwarded() {
+        let proxy = proxy_with(|config| {
+            config.visitor_auth.bearer_tokens = vec![String::from("let-me-in")];
+        });
+        assert_eq!(None, poll(&proxy, Uuid::new_v4()).await);
+
+        for listener in [Listener::Combined, Listener::Public, Listener::Control] {
+            for path in [HEALTH_PATH, READY_PATH] {
+                let response = proxy.call(get(path), peer(), listener).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                assert_eq!("application/json", response.headers()["content-type"]);
+
+                // Not even when they look like they come from a client
+                let request = Request::post(path)
+                    .header("x-proxy-secret", SECRET)
+                    .body(Body::from("{}"))
+                    .unwrap();
+                let response = proxy.call(request, peer(), listener).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+            }
+        }
+
+        assert_eq!(0, proxy.requests.lock().await.len());
+        assert_eq!(0, proxy.in_flight.lock().await.len());
+    }
+
+    #[tokio::test]
+    async fn visitors_may_send_the_secret_header() {
+        let proxy = proxy_with(|config| {
+            config.auth_lockout = Some(request_proxy::auth::Lockout {
+                max_failures: 1,
+                duration: Duration::from_secs(60),
+                max_duration: Duration::from_secs(60),
+            });
+        });
+        let client = Uuid::new_v4();
+
+        let request = Request::get("/page")
+            .header("x-proxy-secret", "not the secret")
+            .body(Body::empty())
+            .unwrap();
+        let visitor = visit(&proxy, request);
+        wait_for_queue(&proxy, 1).await;
+
+        // Forwarded like any other request, and not held against the visitor
+        assert_eq!(
+            None,
+            proxy
+                .check_lockout(peer().ip(), Credentials::Client)
+                .await
+                .map(|r| r.status())
+        );
+        let request_id = poll(&proxy, client).await.unwrap();
+        respond(&proxy, client, request_id).await;
+        assert_eq!("answered", text(visitor.await.unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn visitors_and_clients_are_locked_out_separately() {
+        let proxy = proxy_with(|config| {
+            config.visitor_auth.bearer_tokens = vec![String::from("let-me-in")];
+            config.auth_lockout = Some(request_proxy::auth::Lockout {
+                max_failures: 1,
+                duration: Duration::from_secs(60),
+                max_duration: Duration::from_secs(60),
+            });
+        });
+        let guess = || {
+            Request::get("/")
+                .header("authorization", "Bearer guess")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Behind a proxy without TRUSTED_IP_HEADER, visitors and the client share an address
+        let response = proxy
+            .call(guess(), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let response = proxy
+            .call(guess(), peer(), Listener::Combined)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, response.status());
+
+        let response = proxy
+            .call(
+                control_request(Endpoint::Poll, Uuid::new_v4(), Body::empty()),
+                peer(),
+                Listener::Combined,
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+    }
+
+    #[tokio::test]
+    async fn legacy_clients_are_recognised_by_the_secret_header() {
+        let proxy = proxy_with(|config| config.legacy_control = true);
+
+        let request = Request::get("/")
+            .header("x-proxy-secret", SECRET)
+            .body(Body::empty())
+            .unwrap();
+        let response = proxy
+            .call(request, peer(), Listener::Combined)
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        assert_eq!(0, proxy.requests.lock().await.len());
+    }
+
+    #[tokio::test]
+    async fn outdated_clients_are_told_to_upgrade() {
+        let proxy = proxy_with(|_| {});
+
+        for listener in [Listener::Combined, Listener::Control] {
+            let request = Request::get("/")
+                .header("x-proxy-secret", SECRET)
+                .body(Body::empty())
+                .unwrap();
+            let response = proxy.call(request, peer(), listener).await.unwrap();
+
+            assert_eq!(StatusCode::UPGRADE_REQUIRED, response.status());
+            assert!(text(response).await.contains("/_tunnel/v1"));
+        }
+
+        assert_eq!(0, proxy.requests.lock().await.len());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::time::{timeout_at, Instant};
+use uuid::Uuid;
+
+use request_proxy::types::{Base64Bytes, StreamEvent};
+
+/// Most bytes read from visitors that may wait for a client, before reading pauses
+const MAX_BUFFERED: usize = 4 * 1024 * 1024;
+
+/// Most bytes handed to a client in one poll
+const MAX_BATCH: usize = 1024 * 1024;
+
+/// Most bytes read from a visitor at once
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Visitors' TCP connections, and what happened to them that no client has heard about yet
+pub struct Streams {
+    state: Mutex<State>,
+
+    /// Wakes clients waiting for events
+    events_ready: Notify,
+
+    /// Wakes connections waiting for clients to catch up, or to find out they were closed
+    room_ready: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    /// Events for clients, oldest first
+    outbox: VecDeque<StreamEvent>,
+
+    /// Bytes of data waiting in the outbox
+    buffered: usize,
+
+    connections: HashMap<Uuid, Connection>,
+
+    /// When a client last asked for events
+    last_poll: Option<Instant>,
+}
+
+struct Connection {
+    /// The client carrying the connection, once one has been told about it
+    owner: Option<Uuid>,
+
+    /// Bytes from the client, to be written to the visitor
+    to_visitor: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl State {
+    /// Stop carrying a connection, dropping whatever was still waiting for the client
+    fn forget(&mut self, id: Uuid) {
+        self.connections.remove(&id);
+
+        let mut buffered = self.buffered;
+        self.outbox.retain(|event| match event {
+            _ if event.id() != id => true,
+            StreamEvent::Data { data, .. } => {
+                buffered -= data.0.len();
+                false
+            }
+            _ => false,
+        });
+        self.buffered = buffered;
+    }
+}
+
+impl Streams {
+    pub fn new() -> Streams {
+        Streams {
+            state: Mutex::new(State::default()),
+            events_ready: Notify::new(),
+            room_ready: Notify::new(),
+        }
+    }
+
+    /// Whether a client has asked for events recently enough to take a new connection
+    pub async fn has_client(&self, timeout: Duration) -> bool {
+        self.state
+            .lock()
+            .await
+            .last_poll
+            .is_some_and(|last_poll| last_poll.elapsed() < timeout)
+    }
+
+    /// Start carrying a connection a visitor opened, returning what clients send for it
+    pub async fn open(&self, id: Uuid, remote_addr: IpAddr) -> mpsc::UnboundedReceiver<Vec<u8>> {
+        let (to_visitor, from_client) = mpsc::unbounded_channel();
+
+        {
+            let mut state = self.state.lock().await;
+            state.connections.insert(
+                id,
+                Connection {
+                    owner: None,
+                    to_visitor,
+                },
+            );
+            state.outbox.push_back(StreamEvent::Open {
+                id,
+                remote_addr: Some(remote_addr),
+            });
+        }
+
+        self.events_ready.notify_waiters();
+        from_client
+    }
+
+    /// Pass on bytes a visitor sent, once clients have caught up enough to take them
+    async fn send(&self, id: Uuid, data: Vec<u8>) {
+        loop {
+            let room = self.room_ready.notified();
+
+            {
+                let mut state = self.state.lock().await;
+
+                if !state.connections.contains_key(&id) {
+                    return;
+                }
+
+                if state.buffered < MAX_BUFFERED {
+                    state.buffered += data.len();
+                    state.outbox.push_back(StreamEvent::Data {
+                        id,
+                        data: Base64Bytes(data),
+                    });
+                    break;
+                }
+            }
+
+            room.await;
+        }
+
+        self.events_ready.notify_waiters();
+    }
+
+    /// Tell the client carrying a connection that the visitor closed it
+    async fn close(&self, id: Uuid) {
+        {
+            let mut state = self.state.lock().await;
+
+            match state.connections.get(&id) {
+                Some(connection) if connection.owner.is_some() => {
+                    // Forgotten once the client has been told
+                    state.outbox.push_back(StreamEvent::Close { id });
+                }
+                Some(_) => state.forget(id),
+                None => return,
+            }
+        }
+
+        self.events_ready.notify_waiters();
+        self.room_ready.notify_waiters();
+    }
+
+    /// Events for a client, waiting up to `wait` for there to be any
+    pub async fn take(&self, client: Uuid, wait: Duration) -> Vec<StreamEvent> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let ready = self.events_ready.notified();
+
+            let events = self.take_now(client).await;
+            if !events.is_empty() || timeout_at(deadline, ready).await.is_err() {
+                return events;
+            }
+        }
+    }
+
+    /// Events for a client which are waiting already; new connections go to whoever asks first
+    async fn take_now(&self, client: Uuid) -> Vec<StreamEvent> {
+        let mut state = self.state.lock().await;
+        state.last_poll = Some(Instant::now());
+
+        let mut taken = Vec::new();
+        let mut kept = VecDeque::new();
+        let mut size = 0;
+
+        for event in mem::take(&mut state.outbox) {
+            let id = event.id();
+            let Some(connection) = state.connections.get_mut(&id) else {
+                continue;
+            };
+
+            if size >= MAX_BATCH || connection.owner.is_some_and(|owner| owner != client) {
+                kept.push_back(event);
+                continue;
+            }
+
+            connection.owner = Some(client);
+
+            match &event {
+                StreamEvent::Data { data, .. } => size += data.0.len(),
+                StreamEvent::Close { .. } => {
+                    state.connections.remove(&id);
+                }
+                StreamEvent::Open { .. } => {}
+            }
+
+            taken.push(event);
+        }
+
+        state.outbox = kept;
+        state.buffered -= size;
+
+        if size > 0 {
+            self.room_ready.notify_waiters();
+        }
+
+        taken
+    }
+
+    /// Pass on what a client said happened to its ends of the connections it carries
+    pub async fn receive(&self, client: Uuid, events: Vec<StreamEvent>) {
+        let mut state = self.state.lock().await;
+
+        for event in events {
+            let id = event.id();
+            if state
+                .connections
+                .get(&id)
+                .is_none_or(|connection| connection.owner != Some(client))
+            {
+                continue;
+            }
+
+            match event {
+                StreamEvent::Data { data, .. } => {
+                    let _ = state.connections[&id].to_visitor.send(data.0);
+                }
+                StreamEvent::Close { .. } => state.forget(id),
+                // Only visitors open connections
+                StreamEvent::Open { .. } => {}
+            }
+        }
+
+        drop(state);
+        self.room_ready.notify_waiters();
+    }
+
+    /// Close the connections a client was carrying, since it went away
+    pub async fn release_client(&self, client: Uuid) {
+        {
+            let mut state = self.state.lock().await;
+
+            let owned: Vec<Uuid> = state
+                .connections
+                .iter()
+                .filter(|(_, connection)| connection.owner == Some(client))
+                .map(|(id, _)| *id)
+                .collect();
+
+            for id in owned {
+                state.forget(id);
+            }
+        }
+
+        self.room_ready.notify_waiters();
+    }
+}
+
+/// Carry a visitor's connection until either end closes it
+pub async fn relay(
+    streams: &Streams,
+    id: Uuid,
+    socket: TcpStream,
+    mut from_client: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    let (mut reader, mut writer) = socket.into_split();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+
+    loop {
+        tokio::select! {
+            read = reader.read(&mut buffer) => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => streams.send(id, buffer[..n].to_vec()).await,
+            },
+            data = from_client.recv() => match data {
+                Some(data) => {
+                    if writer.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                // The client closed its end
+                None => return,
+            },
+        }
+    }
+
+    streams.close(id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    fn data(id: Uuid, bytes: &[u8]) -> StreamEvent {
+        StreamEvent::Data {
+            id,
+            data: Base64Bytes(bytes.to_vec()),
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_stay_with_the_client_which_took_them() {
+        let streams = Streams::new();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let id = Uuid::new_v4();
+        let remote_addr = IpAddr::from([127, 0, 0, 1]);
+
+        let mut from_client = streams.open(id, remote_addr).await;
+        streams.send(id, b"hello".to_vec()).await;
+
+        assert_eq!(
+            vec![
+                StreamEvent::Open {
+                    id,
+                    remote_addr: Some(remote_addr)
+                },
+                data(id, b"hello")
+            ],
+            streams.take(first, Duration::ZERO).await
+        );
+
+        // Another client neither hears about the connection nor gets to write to it
+        streams.send(id, b"again".to_vec()).await;
+        assert!(streams.take(second, Duration::ZERO).await.is_empty());
+        streams.receive(second, vec![data(id, b"intruder")]).await;
+
+        streams.receive(first, vec![data(id, b"welcome")]).await;
+        assert_eq!(Some(b"welcome".to_vec()), from_client.recv().await);
+        assert_eq!(
+            vec![data(id, b"again")],
+            streams.take(first, Duration::ZERO).await
+        );
+
+        // Once the client closes its end, there's nothing more to write to the visitor
+        streams
+            .receive(first, vec![StreamEvent::Close { id }])
+            .await;
+        assert_eq!(None, from_client.recv().await);
+    }
+
+    #[tokio::test]
+    async fn waits_for_events_and_releases_connections_with_their_client() {
+        let streams = Arc::new(Streams::new());
+        let client = Uuid::new_v4();
+        let id = Uuid::new_v4();
+
+        assert!(!streams.has_client(Duration::from_secs(30)).await);
+        let waiting = {
+            let streams = streams.clone();
+            tokio::spawn(async move { streams.take(client, Duration::from_secs(5)).await })
+        };
+
+        let mut from_client = streams.open(id, IpAddr::from([127, 0, 0, 1])).await;
+        assert_eq!(1, waiting.await.unwrap().len());
+        assert!(streams.has_client(Duration::from_secs(30)).await);
+
+        streams.release_client(client).await;
+        assert_eq!(None, from_client.recv().await);
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::types::ClientResponse;
+
+/// Visitor requests which were acknowledged on the client's behalf, kept on disk until a client
+/// answers them.
+///
+/// Requests waiting for a client are kept in `pending/` as the JSON that's sent to the client.
+/// Once answered, they're moved to `answered/` along with the client's response.
+pub struct CaptureStore {
+    dir: PathBuf,
+
+    /// IDs of the pending requests, oldest first
+    pending: VecDeque<Uuid>,
+
+    /// When pending requests were last handed to a client
+    delivered: HashMap<Uuid, Instant>,
+
+    /// How long a client has to answer a captured request before it's delivered again
+    redeliver_after: Duration,
+}
+
+impl CaptureStore {
+    /// Use the store in `dir`, picking up any requests still pending from an earlier run
+    pub fn open(dir: &Path, redeliver_after: Duration) -> io::Result<CaptureStore> {
+        fs::create_dir_all(dir.join("pending"))?;
+        fs::create_dir_all(dir.join("answered"))?;
+
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(dir.join("pending"))? {
+            let entry = entry?;
+            let path = entry.path();
+
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .filter(|_| path.extension().is_some_and(|ext| ext == "json"))
+                .and_then(|stem| Uuid::from_str(stem).ok());
+
+            if let Some(id) = id {
+                pending.push((entry.metadata()?.modified()?, id));
+            }
+        }
+        pending.sort();
+
+        Ok(CaptureStore {
+            dir: dir.to_path_buf(),
+            pending: pending.into_iter().map(|(_, id)| id).collect(),
+            delivered: HashMap::new(),
+            redeliver_after,
+        })
+    }
+
+    /// Number of requests waiting for a client
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn is_pending(&self, id: Uuid) -> bool {
+        self.pending.contains(&id)
+    }
+
+    fn pending_path(&self, id: Uuid) -> PathBuf {
+        self.dir
+            .join("pending")
+            .join(format!("{}.json", id.hyphenated()))
+    }
+
+    fn answered_path(&self, id: Uuid) -> PathBuf {
+        self.dir
+            .join("answered")
+            .join(format!("{}.json", id.hyphenated()))
+    }
+
+    /// Persist a serialized `ProxiedRequest` until a client answers it
+    pub fn capture(&mut self, id: Uuid, request: &str) -> io::Result<()> {
+        write_atomically(&self.pending_path(id), request.as_bytes())?;
+        self.pending.push_back(id);
+        Ok(())
+    }
+
+    /// The oldest pending request which isn't waiting on a client already, if any
+    pub fn next_delivery(&mut self, now: Instant) -> io::Result<Option<(Uuid, String)>> {
+        let redeliver_after = self.redeliver_after;
+        let next = self.pending.iter().copied().find(|id| {
+            self.delivered
+                .get(id)
+                .is_none_or(|at| now.saturating_duration_since(*at) >= redeliver_after)
+        });
+
+        let Some(id) = next else {
+            return Ok(None);
+        };
+
+        let request = fs::read_to_string(self.pending_path(id))?;
+        self.delivered.insert(id, now);
+
+        Ok(Some((id, request)))
+    }
+
+    /// Store the client's answer to a pending request alongside the request itself
+    pub fn record_response(&mut self, response: &ClientResponse) -> io::Result<()> {
+        let id = response.request_id;
+        let request: Value = serde_json::from_str(&fs::read_to_string(self.pending_path(id))?)?;
+
+        let record = json!({
+            "answered_at": SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            "request": request,
+            "response": response,
+        });
+
+        write_atomically(
+            &self.answered_path(id),
+            serde_json::to_string_pretty(&record)?.as_bytes(),
+        )?;
+        fs::remove_file(self.pending_path(id))?;
+        sync_dir(&self.dir.join("pending"))?;
+
+        self.pending.retain(|pending| *pending != id);
+        self.delivered.remove(&id);
+
+        Ok(())
+    }
+}
+
+/// Write a file so that it's either complete or missing, even if we crash part way through,
+/// and only return once it's on disk
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    fs::rename(temporary, path)?;
+    sync_dir(path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Flush a directory's entries to disk, so that files renamed into or removed from it stay
+/// that way after a crash
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Only Unix lets directories be opened and flushed like files
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Base64Bytes;
+
+    const REDELIVER: Duration = Duration::from_secs(60);
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("request-proxy-capture-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn delivers_captured_requests_until_answered() {
+        let dir = temp_dir();
+        let mut store = CaptureStore::open(&dir, REDELIVER).unwrap();
+        let now = Instant::now();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        store.capture(first, r#"{"id":"first"}"#).unwrap();
+        store.capture(second, r#"{"id":"second"}"#).unwrap();
+        assert_eq!(2, store.len());
+
+        let delivered = store.next_delivery(now).unwrap();
+        assert_eq!(Some((first, r#"{"id":"first"}"#.to_string())), delivered);
+        assert_eq!(Some(second), store.next_delivery(now).unwrap().map(|d| d.0));
+        assert_eq!(None, store.next_delivery(now).unwrap());
+
+        // Nobody answered in time, so the first one goes out again
+        assert_eq!(
+            Some(first),
+            store.next_delivery(now + REDELIVER).unwrap().map(|d| d.0)
+        );
+
+        store
+            .record_response(&ClientResponse {
+                request_id: first,
+                status: 200,
+                headers: Vec::new(),
+                body: Base64Bytes(b"ok".to_vec()),
+            })
+            .unwrap();
+
+        assert!(!store.is_pending(first));
+        assert!(store.is_pending(second));
+
+        let answered: Value = serde_json::from_str(
+            &fs::read_to_string(dir.join("answered").join(format!("{}.json", first))).unwrap(),
+        )
+        .unwrap();
+        assert_eq!("first", answered["request"]["id"]);
+        assert_eq!(200, answered["response"]["status"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn picks_up_pending_requests_after_a_restart() {
+        let dir = temp_dir();
+        let id = Uuid::new_v4();
+
+        CaptureStore::open(&dir, REDELIVER)
+            .unwrap()
+            .capture(id, "{}")
+            .unwrap();
+
+        let store = CaptureStore::open(&dir, REDELIVER).unwrap();
+        assert!(store.is_pending(id));
+        assert_eq!(1, store.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
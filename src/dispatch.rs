@@ -0,0 +1,360 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use hyper::Method;
+use uuid::Uuid;
+
+/// Methods which may be sent more than once without changing the outcome (RFC 7231)
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+/// A request which has been handed to a client, but not yet answered
+struct Dispatched<T> {
+    /// The client which received the request, if it identified itself
+    client: Option<Uuid>,
+
+    /// When the request was first handed out
+    since: Instant,
+
+    /// When the client's lease on the request runs out, unless it's been taken back already
+    lease_expires: Option<Instant>,
+
+    /// A copy of the request, kept if it may safely be handed out again
+    retained: Option<T>,
+}
+
+/// How a request stopped being in flight
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Settled {
+    Answered,
+
+    /// The visitor was answered without waiting for the client any longer
+    Expired,
+}
+
+/// Why a client's response to a request was turned away
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    /// The request was never handed to a client, or was settled too long ago to remember
+    Unknown,
+
+    /// The request has already been answered
+    AlreadyAnswered,
+
+    /// The visitor stopped waiting before the response arrived
+    Expired,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::Unknown => write!(f, "no such request is waiting for a response"),
+            Rejection::AlreadyAnswered => write!(f, "the request has already been answered"),
+            Rejection::Expired => write!(f, "the request expired before it was answered"),
+        }
+    }
+}
+
+/// Tracks requests which were handed to a client, so that only one response is accepted for
+/// each, and only while the visitor is still waiting for it.
+///
+/// Settled requests are remembered for a while, so late and duplicate responses can be told
+/// apart from ones which are simply wrong.
+///
+/// Each client only holds a request for the length of its lease. Requests handed out with a
+/// retained copy are taken back when the lease runs out or the client goes away, so they can
+/// be handed to another client; any response to them is still accepted in the meantime.
+pub struct InFlight<T> {
+    /// How long a visitor waits for a response
+    timeout: Duration,
+
+    /// How long settled requests are remembered
+    retention: Duration,
+
+    /// How long a client has to answer a request before it may be taken back, if ever
+    lease: Option<Duration>,
+
+    dispatched: HashMap<Uuid, Dispatched<T>>,
+    settled: HashMap<Uuid, (Settled, Instant)>,
+}
+
+impl<T> InFlight<T> {
+    pub fn new(timeout: Duration, retention: Duration, lease: Option<Duration>) -> InFlight<T> {
+        InFlight {
+            timeout,
+            retention,
+            lease,
+            dispatched: HashMap::new(),
+            settled: HashMap::new(),
+        }
+    }
+
+    /// Number of requests waiting on a client
+    pub fn len(&self) -> usize {
+        self.dispatched.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dispatched.is_empty()
+    }
+
+    /// Record that a request was handed to `client`, keeping a copy of it if it may be
+    /// handed out again. Handing out a request that was taken back renews its lease.
+    pub fn dispatch(
+        &mut self,
+        request_id: Uuid,
+        client: Option<Uuid>,
+        retained: Option<T>,
+        now: Instant,
+    ) {
+        self.prune(now);
+
+        let lease_expires = self.lease.map(|lease| now + lease);
+        let since = self
+            .dispatched
+            .get(&request_id)
+            .map_or(now, |dispatched| dispatched.since);
+
+        self.dispatched.insert(
+            request_id,
+            Dispatched {
+                client,
+                since,
+                lease_expires,
+                retained,
+            },
+        );
+    }
+
+    /// Take back the retained requests whose lease has run out, so they can be handed out again
+    pub fn reclaim_expired_leases(&mut self, now: Instant) -> Vec<(Uuid, T)> {
+        self.prune(now);
+
+        let mut reclaimed = Vec::new();
+
+        for (request_id, dispatched) in self.dispatched.iter_mut() {
+            if dispatched
+                .lease_expires
+                .is_some_and(|expires| now >= expires)
+            {
+                dispatched.lease_expires = None;
+
+                // Anything not retained stays with its client until the visitor gives up
+                if let Some(request) = dispatched.retained.take() {
+                    dispatched.client = None;
+                    reclaimed.push((*request_id, request));
+                }
+            }
+        }
+
+        reclaimed
+    }
+
+    /// Accept a response to the request, if it's still waiting for one
+    pub fn answer(&mut self, request_id: Uuid, now: Instant) -> Result<(), Rejection> {
+        self.prune(now);
+
+        if self.dispatched.remove(&request_id).is_some() {
+            self.settled.insert(request_id, (Settled::Answered, now));
+            return Ok(());
+        }
+
+        match self.settled.get(&request_id) {
+            Some((Settled::Answered, _)) => Err(Rejection::AlreadyAnswered),
+            Some((Settled::Expired, _)) => Err(Rejection::Expired),
+            None => Err(Rejection::Unknown),
+        }
+    }
+
+    /// Stop waiting for a response to the request, eg: because the visitor gave up on it.
+    ///
+    /// Returns whether the request was in flight at all.
+    pub fn expire(&mut self, request_id: Uuid, now: Instant) -> bool {
+        let was_dispatched = self.dispatched.remove(&request_id).is_some();
+
+        if was_dispatched {
+            self.settled.insert(request_id, (Settled::Expired, now));
+        }
+
+        was_dispatched
+    }
+
+    /// Take back everything handed to `client`, which has gone away.
+    ///
+    /// Retained requests are returned so they can be handed out again; the rest are expired,
+    /// and their IDs returned so that their visitors can be told.
+    pub fn release_client(&mut self, client: Uuid, now: Instant) -> (Vec<(Uuid, T)>, Vec<Uuid>) {
+        let mut reclaimed = Vec::new();
+        let mut abandoned = Vec::new();
+
+        for (request_id, dispatched) in self.dispatched.iter_mut() {
+            if dispatched.client != Some(client) {
+                continue;
+            }
+
+            dispatched.client = None;
+            dispatched.lease_expires = None;
+
+            match dispatched.retained.take() {
+                Some(request) => reclaimed.push((*request_id, request)),
+                None => abandoned.push(*request_id),
+            }
+        }
+
+        for request_id in &abandoned {
+            self.expire(*request_id, now);
+        }
+
+        (reclaimed, abandoned)
+    }
+
+    /// Stop waiting on everything, returning the requests which were still in flight
+    pub fn expire_all(&mut self, now: Instant) -> Vec<Uuid> {
+        let request_ids: Vec<Uuid> = self.dispatched.keys().copied().collect();
+
+        for request_id in &request_ids {
+            self.expire(*request_id, now);
+        }
+
+        request_ids
+    }
+
+    /// Expire requests the visitor can no longer be waiting on, and forget old settled ones
+    fn prune(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        let stale: Vec<Uuid> = self
+            .dispatched
+            .iter()
+            .filter(|(_, dispatched)| now.saturating_duration_since(dispatched.since) > timeout)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+
+        for request_id in stale {
+            self.expire(request_id, now);
+        }
+
+        let retention = self.retention;
+        self.settled
+            .retain(|_, (_, at)| now.saturating_duration_since(*at) <= retention);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(15);
+    const RETENTION: Duration = Duration::from_secs(60);
+    const LEASE: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn only_idempotent_methods_are_idempotent() {
+        assert!(is_idempotent(&Method::GET));
+        assert!(is_idempotent(&Method::PUT));
+        assert!(!is_idempotent(&Method::POST));
+        assert!(!is_idempotent(&Method::PATCH));
+    }
+
+    #[test]
+    fn accepts_one_response_per_request() {
+        let mut in_flight: InFlight<&str> = InFlight::new(TIMEOUT, RETENTION, None);
+        let now = Instant::now();
+        let id = Uuid::new_v4();
+
+        assert_eq!(Err(Rejection::Unknown), in_flight.answer(id, now));
+
+        in_flight.dispatch(id, None, None, now);
+        assert_eq!(1, in_flight.len());
+        assert_eq!(Ok(()), in_flight.answer(id, now));
+        assert!(in_flight.is_empty());
+
+        assert_eq!(
+            Err(Rejection::AlreadyAnswered),
+            in_flight.answer(id, now + Duration::from_secs(1))
+        );
+
+        // Eventually the duplicate can't be recognised any more
+        assert_eq!(
+            Err(Rejection::Unknown),
+            in_flight.answer(id, now + RETENTION * 2)
+        );
+    }
+
+    #[test]
+    fn rejects_late_responses() {
+        let mut in_flight: InFlight<&str> = InFlight::new(TIMEOUT, RETENTION, None);
+        let now = Instant::now();
+
+        // Expired explicitly, when the visitor stopped waiting
+        let given_up = Uuid::new_v4();
+        in_flight.dispatch(given_up, None, None, now);
+        assert!(in_flight.expire(given_up, now + TIMEOUT));
+        assert_eq!(
+            Err(Rejection::Expired),
+            in_flight.answer(given_up, now + TIMEOUT)
+        );
+
+        // Expired for having been in flight longer than any visitor waits
+        let forgotten = Uuid::new_v4();
+        in_flight.dispatch(forgotten, None, None, now);
+        assert_eq!(
+            Err(Rejection::Expired),
+            in_flight.answer(forgotten, now + TIMEOUT * 2)
+        );
+        assert!(in_flight.is_empty());
+    }
+
+    #[test]
+    fn releases_requests_held_by_a_departed_client() {
+        let mut in_flight = InFlight::new(TIMEOUT, RETENTION, Some(LEASE));
+        let now = Instant::now();
+        let (client, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let (get, post, theirs) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        in_flight.dispatch(get, Some(client), Some("GET"), now);
+        in_flight.dispatch(post, Some(client), None, now);
+        in_flight.dispatch(theirs, Some(other), Some("GET"), now);
+
+        let (reclaimed, abandoned) = in_flight.release_client(client, now);
+        assert_eq!(vec![(get, "GET")], reclaimed);
+        assert_eq!(vec![post], abandoned);
+
+        // The reclaimed request is still waiting for an answer; the abandoned one isn't
+        assert_eq!(Err(Rejection::Expired), in_flight.answer(post, now));
+        assert_eq!(Ok(()), in_flight.answer(get, now));
+        assert_eq!(Ok(()), in_flight.answer(theirs, now));
+    }
+
+    #[test]
+    fn reclaims_retained_requests_when_their_lease_expires() {
+        let mut in_flight = InFlight::new(TIMEOUT, RETENTION, Some(LEASE));
+        let now = Instant::now();
+        let (get, post) = (Uuid::new_v4(), Uuid::new_v4());
+
+        in_flight.dispatch(get, None, Some("GET"), now);
+        in_flight.dispatch(post, None, None, now);
+
+        assert!(in_flight.reclaim_expired_leases(now).is_empty());
+        assert_eq!(
+            vec![(get, "GET")],
+            in_flight.reclaim_expired_leases(now + LEASE)
+        );
+
+        // Only reclaimed once, until it's handed out again with a new lease
+        assert!(in_flight.reclaim_expired_leases(now + LEASE).is_empty());
+        in_flight.dispatch(get, None, Some("GET"), now + LEASE);
+        assert_eq!(
+            vec![(get, "GET")],
+            in_flight.reclaim_expired_leases(now + LEASE * 2)
+        );
+
+        // Even after being taken back, the original client's answer is accepted
+        assert_eq!(Ok(()), in_flight.answer(get, now + LEASE * 2));
+        assert_eq!(Ok(()), in_flight.answer(post, now + LEASE * 2));
+    }
+}
@@ -0,0 +1,189 @@
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderValue};
+
+/// Headers describing a forwarded request, as defined by RFC 7239 and the de-facto `X-Forwarded-*`
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+];
+
+/// Who originally sent a request, and how, as seen by the server
+pub struct ForwardedFor<'a> {
+    pub addr: IpAddr,
+    pub proto: &'a str,
+    pub host: Option<&'a str>,
+}
+
+/// Which headers to use to tell the internal service about the original request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ForwardingStyle {
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    pub x_forwarded: bool,
+
+    /// RFC 7239 `Forwarded`
+    pub forwarded: bool,
+
+    /// Extend the forwarding headers the visitor sent, rather than replacing them.
+    ///
+    /// Only safe when everything in front of the server can be trusted to set them honestly.
+    pub trust_existing: bool,
+}
+
+/// Add headers describing the original request
+pub fn add_forwarding_headers(
+    headers: &mut HeaderMap,
+    original: &ForwardedFor,
+    style: ForwardingStyle,
+) {
+    if !style.trust_existing {
+        for name in FORWARDING_HEADERS {
+            headers.remove(name);
+        }
+    }
+
+    if style.x_forwarded {
+        append_to_list(headers, "x-forwarded-for", &original.addr.to_string());
+
+        // These describe the first hop, so a trusted value is kept as it is
+        set_unless_present(headers, "x-forwarded-proto", original.proto);
+        if let Some(host) = original.host {
+            set_unless_present(headers, "x-forwarded-host", host);
+        }
+    }
+
+    if style.forwarded {
+        append_to_list(headers, "forwarded", &forwarded_element(original));
+    }
+}
+
+/// Append `value` to the comma-separated list in the header `name`
+fn append_to_list(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    let existing: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .collect();
+
+    let list = if existing.is_empty() {
+        value.to_string()
+    } else {
+        format!("{}, {}", existing.join(", "), value)
+    };
+
+    if let Ok(list) = HeaderValue::from_str(&list) {
+        headers.insert(name, list);
+    }
+}
+
+fn set_unless_present(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if !headers.contains_key(name) {
+        if let Ok(value) = HeaderValue::from_str(value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Describe the original request as a `Forwarded` element, eg: `for=192.0.2.60;proto=https`
+fn forwarded_element(original: &ForwardedFor) -> String {
+    let addr = match original.addr {
+        IpAddr::V4(addr) => addr.to_string(),
+        // IPv6 addresses are bracketed and quoted, since they contain colons
+        IpAddr::V6(addr) => format!("\"[{}]\"", addr),
+    };
+
+    let mut element = format!("for={};proto={}", addr, quote(original.proto));
+
+    if let Some(host) = original.host {
+        element.push_str(";host=");
+        element.push_str(&quote(host));
+    }
+
+    element
+}
+
+/// Quote a `Forwarded` parameter value, unless it's a plain token
+fn quote(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    const BOTH: ForwardingStyle = ForwardingStyle {
+        x_forwarded: true,
+        forwarded: true,
+        trust_existing: false,
+    };
+
+    fn original(addr: &str) -> ForwardedFor<'static> {
+        ForwardedFor {
+            addr: IpAddr::from_str(addr).unwrap(),
+            proto: "https",
+            host: Some("example.test:8443"),
+        }
+    }
+
+    #[test]
+    fn describes_the_original_request() {
+        let mut headers = HeaderMap::new();
+        add_forwarding_headers(&mut headers, &original("203.0.113.7"), BOTH);
+
+        assert_eq!("203.0.113.7", headers["x-forwarded-for"]);
+        assert_eq!("https", headers["x-forwarded-proto"]);
+        assert_eq!("example.test:8443", headers["x-forwarded-host"]);
+        assert_eq!(
+            "for=203.0.113.7;proto=https;host=\"example.test:8443\"",
+            headers["forwarded"]
+        );
+
+        let mut headers = HeaderMap::new();
+        add_forwarding_headers(&mut headers, &original("2001:db8::1"), BOTH);
+        assert_eq!(
+            "for=\"[2001:db8::1]\";proto=https;host=\"example.test:8443\"",
+            headers["forwarded"]
+        );
+    }
+
+    #[test]
+    fn replaces_untrusted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("forwarded", "for=10.0.0.1".parse().unwrap());
+
+        add_forwarding_headers(&mut headers, &original("203.0.113.7"), BOTH);
+        assert_eq!("203.0.113.7", headers["x-forwarded-for"]);
+        assert_eq!("https", headers["x-forwarded-proto"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("forwarded", "for=10.0.0.1".parse().unwrap());
+
+        let trusting = ForwardingStyle {
+            trust_existing: true,
+            ..BOTH
+        };
+        add_forwarding_headers(&mut headers, &original("203.0.113.7"), trusting);
+        assert_eq!("10.0.0.1, 203.0.113.7", headers["x-forwarded-for"]);
+        assert_eq!("http", headers["x-forwarded-proto"]);
+        assert!(headers["forwarded"]
+            .to_str()
+            .unwrap()
+            .starts_with("for=10.0.0.1, for=203.0.113.7;"));
+    }
+}
@@ -0,0 +1,215 @@
+use std::str::FromStr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION};
+
+/// Headers which only describe a single connection, and must not be passed on by proxies (RFC 7230)
+pub const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Prefix of the headers used to control the tunnel itself
+pub const CONTROL_HEADER_PREFIX: &str = "x-proxy-";
+
+/// Remove the hop-by-hop headers, including any listed in `Connection`
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// Remove the `x-proxy-*` headers, so that they never reach the internal service or a visitor
+pub fn strip_control_headers(headers: &mut HeaderMap) {
+    let control: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(CONTROL_HEADER_PREFIX))
+        .cloned()
+        .collect();
+
+    for name in control {
+        headers.remove(name);
+    }
+}
+
+/// A change to make to a set of headers
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeaderRule {
+    /// Add a value, keeping any the header already has
+    Add(HeaderName, HeaderValue),
+
+    /// Replace every value of the header
+    Set(HeaderName, HeaderValue),
+
+    Remove(HeaderName),
+
+    /// Move every value of the first header to the second
+    Rename(HeaderName, HeaderName),
+}
+
+impl HeaderRule {
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        match self {
+            HeaderRule::Add(name, value) => {
+                headers.append(name, value.clone());
+            }
+            HeaderRule::Set(name, value) => {
+                headers.insert(name, value.clone());
+            }
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            }
+            HeaderRule::Rename(from, to) => {
+                let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+                headers.remove(from);
+
+                for value in values {
+                    headers.append(to, value);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    /// Parse a rule such as `set X-Frame-Options: DENY`, `remove Server`,
+    /// or `rename X-Old: X-New`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (action, rest) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Invalid header rule '{}'", s))?;
+
+        let (name, value) = match rest.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim())),
+            None => (rest.trim(), None),
+        };
+
+        let name = HeaderName::from_str(name)
+            .map_err(|_| format!("Invalid header name '{}' in rule '{}'", name, s))?;
+        let parse_value = |v: &str| {
+            HeaderValue::from_str(v).map_err(|_| format!("Invalid header value in rule '{}'", s))
+        };
+
+        match (action.to_ascii_lowercase().as_str(), value) {
+            ("add", Some(v)) => Ok(HeaderRule::Add(name, parse_value(v)?)),
+            ("set", Some(v)) => Ok(HeaderRule::Set(name, parse_value(v)?)),
+            ("remove", None) => Ok(HeaderRule::Remove(name)),
+            ("rename", Some(to)) => HeaderName::from_str(to)
+                .map(|to| HeaderRule::Rename(name, to))
+                .map_err(|_| format!("Invalid header name '{}' in rule '{}'", to, s)),
+            _ => Err(format!(
+                "Invalid header rule '{}'; expected add/set NAME: VALUE, remove NAME, or rename NAME: NEW",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse one rule per line, skipping blank lines and `#` comments
+pub fn parse_header_rules(rules: &str) -> Result<Vec<HeaderRule>, String> {
+    rules
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(HeaderRule::from_str)
+        .collect()
+}
+
+/// Apply the rules to the headers, in order
+pub fn apply_header_rules(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        rule.apply(headers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("connection", "keep-alive, X-Custom-Hop".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.insert("transfer-encoding", "chunked".parse().unwrap());
+        headers.insert("x-custom-hop", "1".parse().unwrap());
+        headers.insert("content-type", "text/plain".parse().unwrap());
+
+        strip_hop_by_hop(&mut headers);
+
+        assert_eq!(1, headers.len());
+        assert_eq!("text/plain", headers["content-type"]);
+    }
+
+    #[test]
+    fn strips_control_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-proxy-secret", "hunter2".parse().unwrap());
+        headers.insert("x-proxy-client-id", "abc".parse().unwrap());
+        headers.insert("x-proxied-by", "someone".parse().unwrap());
+
+        strip_control_headers(&mut headers);
+
+        assert_eq!(1, headers.len());
+        assert!(headers.contains_key("x-proxied-by"));
+    }
+
+    #[test]
+    fn applies_header_rules() {
+        let rules = parse_header_rules(
+            "# Security headers
+            set Strict-Transport-Security: max-age=31536000; includeSubDomains
+            add Vary: Origin
+            remove Server
+            rename X-Powered-By: X-Backend",
+        )
+        .unwrap();
+        assert_eq!(4, rules.len());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("strict-transport-security", "max-age=0".parse().unwrap());
+        headers.insert("vary", "Accept".parse().unwrap());
+        headers.insert("server", "nginx".parse().unwrap());
+        headers.insert("x-powered-by", "php".parse().unwrap());
+
+        apply_header_rules(&rules, &mut headers);
+
+        assert_eq!(
+            "max-age=31536000; includeSubDomains",
+            headers["strict-transport-security"]
+        );
+        assert_eq!(2, headers.get_all("vary").iter().count());
+        assert!(!headers.contains_key("server"));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert_eq!("php", headers["x-backend"]);
+    }
+
+    #[test]
+    fn rejects_invalid_header_rules() {
+        assert!(HeaderRule::from_str("set X-Missing-Value").is_err());
+        assert!(HeaderRule::from_str("remove Server: nginx").is_err());
+        assert!(HeaderRule::from_str("replace Server: x").is_err());
+        assert!(HeaderRule::from_str("add Bad Name: x").is_err());
+    }
+}
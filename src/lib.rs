@@ -9,4 +9,18 @@ extern crate tokio;
 extern crate uuid;
 extern crate void;
 
+pub mod acl;
+pub mod auth;
+pub mod capture;
+pub mod dispatch;
+pub mod forwarded;
+pub mod headers;
+pub mod mock;
+pub mod protocol;
+pub mod queue;
+pub mod ratelimit;
+pub mod rewrite;
+pub mod signal;
 pub mod types;
+pub mod visitor;
+pub mod webhook;
@@ -0,0 +1,314 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use hyper::{Body, Response, StatusCode};
+
+/// What the server answers with, on the client's behalf
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockBody {
+    /// A single file
+    File(PathBuf),
+
+    /// Files below a directory, by the rest of the request path
+    Directory(PathBuf),
+
+    Json(String),
+    Text(String),
+    Html(String),
+}
+
+/// A response the server sends without involving the client
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockResponse {
+    pub status: StatusCode,
+    pub body: MockBody,
+}
+
+impl FromStr for MockResponse {
+    type Err = String;
+
+    /// Parse a response such as `json {"ok":true}`, `503 file ./maintenance.html`,
+    /// or `dir ./public`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.trim().splitn(2, char::is_whitespace);
+        let first = words.next().unwrap_or_default();
+        let rest = words.next().unwrap_or_default().trim_start();
+
+        // The status is optional
+        let (status, kind, argument) = match StatusCode::from_str(first) {
+            Ok(status) => {
+                let (kind, argument) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                (status, kind, argument.trim_start())
+            }
+            Err(_) => (StatusCode::OK, first, rest),
+        };
+
+        let body = match kind {
+            "file" if !argument.is_empty() => MockBody::File(PathBuf::from(argument)),
+            "dir" if !argument.is_empty() => MockBody::Directory(PathBuf::from(argument)),
+            "json" => MockBody::Json(argument.to_string()),
+            "text" => MockBody::Text(argument.to_string()),
+            "html" => MockBody::Html(argument.to_string()),
+            _ => {
+                return Err(format!(
+                    "Invalid response '{}'; expected [STATUS] file PATH, dir PATH, json BODY, \
+                     text BODY or html BODY",
+                    s
+                ))
+            }
+        };
+
+        Ok(MockResponse { status, body })
+    }
+}
+
+impl MockResponse {
+    /// Build the response to a request for `path`, which is relative to the route's prefix
+    pub fn respond(&self, path: &str) -> Response<Body> {
+        let (content_type, body) = match &self.body {
+            MockBody::Json(json) => ("application/json", json.clone().into_bytes()),
+            MockBody::Text(text) => ("text/plain; charset=utf-8", text.clone().into_bytes()),
+            MockBody::Html(html) => ("text/html; charset=utf-8", html.clone().into_bytes()),
+            MockBody::File(file) => match fs::read(file) {
+                Ok(contents) => (content_type_of(file), contents),
+                Err(e) => {
+                    eprintln!("ERROR: Failed to read {}! {}", file.display(), e);
+                    return not_found_response();
+                }
+            },
+            MockBody::Directory(dir) => {
+                let file = match file_below(dir, path) {
+                    Some(file) if file.is_dir() => file.join("index.html"),
+                    Some(file) => file,
+                    None => return not_found_response(),
+                };
+
+                match fs::read(&file) {
+                    Ok(contents) => (content_type_of(&file), contents),
+                    Err(_) => return not_found_response(),
+                }
+            }
+        };
+
+        Response::builder()
+            .status(self.status)
+            .header("content-type", content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+/// Requests below a path prefix, answered by the server itself
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockRoute {
+    pub prefix: String,
+
+    /// Whether visitors are answered without the `VISITOR_*` credentials
+    pub public: bool,
+
+    pub response: MockResponse,
+}
+
+impl FromStr for MockRoute {
+    type Err = String;
+
+    /// Parse a route such as `/robots.txt public file ./robots.txt` or
+    /// `/health json {"ok":true}`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, response) = s
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("Invalid route '{}'; expected PREFIX [public] RESPONSE", s))?;
+
+        if !prefix.starts_with('/') {
+            return Err(format!("Route prefix '{}' must start with '/'", prefix));
+        }
+
+        let response = response.trim_start();
+        let (public, response) = match response.split_once(char::is_whitespace) {
+            Some(("public", rest)) => (true, rest),
+            _ => (false, response),
+        };
+
+        Ok(MockRoute {
+            prefix: prefix.to_string(),
+            public,
+            response: MockResponse::from_str(response)?,
+        })
+    }
+}
+
+impl MockRoute {
+    /// The rest of `path` below this route's prefix, if the route covers it
+    fn strip(&self, path: &str) -> Option<String> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+
+        if self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/') {
+            Some(rest.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Parse one route per line, skipping blank lines and `#` comments
+pub fn parse_mock_routes(routes: &str) -> Result<Vec<MockRoute>, String> {
+    routes
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(MockRoute::from_str)
+        .collect()
+}
+
+/// Answer a request for `path` from the route with the longest matching prefix, if any
+pub fn mock_response(routes: &[MockRoute], path: &str) -> Option<Response<Body>> {
+    best_route(routes, path).map(|(route, rest)| route.response.respond(&rest))
+}
+
+/// Answer a request for `path` as `mock_response` would, but only if that route is public
+pub fn public_mock_response(routes: &[MockRoute], path: &str) -> Option<Response<Body>> {
+    best_route(routes, path)
+        .filter(|(route, _)| route.public)
+        .map(|(route, rest)| route.response.respond(&rest))
+}
+
+/// The route with the longest prefix covering `path`, and the rest of the path below it
+fn best_route<'a>(routes: &'a [MockRoute], path: &str) -> Option<(&'a MockRoute, String)> {
+    routes
+        .iter()
+        .filter_map(|route| route.strip(path).map(|rest| (route, rest)))
+        .max_by_key(|(route, _)| route.prefix.len())
+}
+
+/// The file at `path` below `dir`, as long as it really is below `dir`
+fn file_below(dir: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path.trim_start_matches('/'));
+
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    Some(dir.join(relative))
+}
+
+fn content_type_of(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn not_found_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(Body::from("🔍 Not found"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_routes() {
+        let routes = parse_mock_routes(
+            r#"# Keep these working while the client is down
+            /robots.txt file ./robots.txt
+            /health public json {"ok": true}
+            / 503 html <h1>Down for maintenance</h1>"#,
+        )
+        .unwrap();
+
+        assert_eq!(3, routes.len());
+        assert_eq!(
+            vec![false, true, false],
+            routes.iter().map(|route| route.public).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            MockResponse {
+                status: StatusCode::OK,
+                body: MockBody::Json(String::from(r#"{"ok": true}"#)),
+            },
+            routes[1].response
+        );
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, routes[2].response.status);
+        assert_eq!(
+            MockBody::Html(String::from("<h1>Down for maintenance</h1>")),
+            routes[2].response.body
+        );
+
+        assert!(MockRoute::from_str("health json {}").is_err());
+        assert!(MockRoute::from_str("/health").is_err());
+        assert!(MockRoute::from_str("/static dir").is_err());
+        assert!(MockRoute::from_str("/x 200 xml <a/>").is_err());
+        assert!(MockRoute::from_str("/x public").is_err());
+    }
+
+    #[test]
+    fn routes_by_longest_prefix() {
+        let routes = parse_mock_routes(
+            "/health text ok
+            /health/deep 500 text broken",
+        )
+        .unwrap();
+
+        let status = |path| mock_response(&routes, path).map(|r| r.status().as_u16());
+
+        assert_eq!(Some(200), status("/health"));
+        assert_eq!(Some(500), status("/health/deep"));
+        assert_eq!(None, status("/healthz"));
+        assert_eq!(None, status("/"));
+    }
+
+    #[test]
+    fn public_routes_are_answered_only_if_they_match_best() {
+        let routes = parse_mock_routes(
+            "/ public text welcome
+            /admin text private",
+        )
+        .unwrap();
+
+        let status = |path| public_mock_response(&routes, path).map(|r| r.status().as_u16());
+
+        assert_eq!(Some(200), status("/about"));
+        assert_eq!(None, status("/admin/users"));
+    }
+
+    #[test]
+    fn serves_files_below_a_directory_only() {
+        let dir = Path::new("/srv/public");
+
+        assert_eq!(
+            Some(PathBuf::from("/srv/public/css/site.css")),
+            file_below(dir, "/css/site.css")
+        );
+        assert_eq!(None, file_below(dir, "/../secret"));
+        assert_eq!(None, file_below(dir, "/css/../../secret"));
+        assert_eq!(Some(PathBuf::from("/srv/public/")), file_below(dir, "/"));
+    }
+}
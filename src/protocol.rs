@@ -0,0 +1,420 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hyper::Method;
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the endpoints clients use to control the tunnel, kept apart from visitor traffic
+pub const CONTROL_PREFIX: &str = "/_tunnel/";
+
+/// Where clients find out which protocol versions a server speaks.
+///
+/// Clients ask with `OPTIONS`, which servers predating the control endpoints refuse with
+/// `405 Method Not Allowed` rather than mistaking for a poll; any method gets an answer, though.
+pub const VERSIONS_PATH: &str = "/_tunnel/versions";
+
+/// How long the server keeps a visitor waiting for a client to answer their request
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Versions of the control protocol this build speaks, oldest first
+pub const PROTOCOL_VERSIONS: [u32; 1] = [1];
+
+/// The answer to a request for `VERSIONS_PATH`
+#[derive(Serialize, Deserialize)]
+pub struct Versions {
+    pub versions: Vec<u32>,
+}
+
+/// Optional parts of the protocol, used only once both sides have said they support them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Bodies sent as raw bytes, rather than base64 inside JSON
+    BinaryBodies,
+
+    /// Bodies sent in pieces as they're produced, rather than all at once
+    Streaming,
+
+    /// Control messages compressed with gzip
+    Compression,
+
+    /// Visitors' WebSocket connections carried through the tunnel
+    #[serde(rename = "websockets")]
+    WebSockets,
+
+    /// Something only a newer build knows about
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    fn name(&self) -> &'static str {
+        match self {
+            Capability::BinaryBodies => "binary_bodies",
+            Capability::Streaming => "streaming",
+            Capability::Compression => "compression",
+            Capability::WebSockets => "websockets",
+            Capability::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Capability::BinaryBodies,
+            Capability::Streaming,
+            Capability::Compression,
+            Capability::WebSockets,
+        ]
+        .into_iter()
+        .find(|capability| capability.name() == s.trim().to_ascii_lowercase())
+        .ok_or_else(|| format!("Unknown capability '{}'", s))
+    }
+}
+
+/// Capabilities this build supports
+pub const CAPABILITIES: [Capability; 2] = [Capability::BinaryBodies, Capability::Compression];
+
+/// What a client says about itself before its first poll, and what the server says back.
+///
+/// The client lists everything it supports; the server answers with the ones both sides do,
+/// which are all that either side may use from then on.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+/// The capabilities in both lists, leaving out any neither side understands
+pub fn agree(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .filter(|capability| **capability != Capability::Unknown && theirs.contains(capability))
+        .copied()
+        .collect()
+}
+
+/// How requests and responses are encoded on their way through the tunnel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireFormat {
+    /// JSON, with bodies and header values encoded as base64; understood by every client
+    Json,
+
+    /// MessagePack, with bodies and header values kept as raw bytes
+    MessagePack,
+}
+
+impl WireFormat {
+    /// The format to use once these capabilities have been agreed
+    pub fn agreed(capabilities: &[Capability]) -> WireFormat {
+        if capabilities.contains(&Capability::BinaryBodies) {
+            WireFormat::MessagePack
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            WireFormat::Json => "application/json",
+            WireFormat::MessagePack => "application/msgpack",
+        }
+    }
+
+    /// The format of a message with this `content-type`; JSON unless it says otherwise
+    pub fn from_content_type(content_type: Option<&str>) -> WireFormat {
+        let essence = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|essence| essence.trim().to_ascii_lowercase());
+
+        match essence.as_deref() {
+            Some("application/msgpack") | Some("application/x-msgpack") => WireFormat::MessagePack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, message: &T) -> Vec<u8> {
+        match self {
+            WireFormat::Json => serde_json::to_vec(message).expect("Failed to serialize to JSON"),
+
+            // Fields are named, so that either side may add optional ones later
+            WireFormat::MessagePack => {
+                rmp_serde::to_vec_named(message).expect("Failed to serialize to MessagePack")
+            }
+        }
+    }
+
+    pub fn decode<'a, T: Deserialize<'a>>(&self, message: &'a [u8]) -> Result<T, String> {
+        match self {
+            WireFormat::Json => serde_json::from_slice(message).map_err(|e| e.to_string()),
+            WireFormat::MessagePack => rmp_serde::from_slice(message).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Something a client asks the server to do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endpoint {
+    /// Hand over the next visitor request, if there is one
+    Poll,
+
+    /// Accept the response to a visitor request
+    Respond,
+
+    /// Forget about this client, which is going away
+    Disconnect,
+
+    /// Agree on the capabilities to use, before the first poll
+    Hello,
+
+    /// Wait for things to happen to visitors' TCP connections
+    StreamPoll,
+
+    /// Pass on what happened to the local ends of TCP connections
+    StreamSend,
+}
+
+impl Endpoint {
+    const ALL: [Endpoint; 6] = [
+        Endpoint::Poll,
+        Endpoint::Respond,
+        Endpoint::Disconnect,
+        Endpoint::Hello,
+        Endpoint::StreamPoll,
+        Endpoint::StreamSend,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Endpoint::Poll => "poll",
+            Endpoint::Respond => "respond",
+            Endpoint::Disconnect => "disconnect",
+            Endpoint::Hello => "hello",
+            Endpoint::StreamPoll => "streams/poll",
+            Endpoint::StreamSend => "streams/send",
+        }
+    }
+
+    /// Path of the endpoint in the given protocol version, eg: `/_tunnel/v1/poll`
+    pub fn path(&self, version: u32) -> String {
+        format!("{}v{}/{}", CONTROL_PREFIX, version, self.name())
+    }
+
+    pub fn method(&self) -> Method {
+        match self {
+            Endpoint::Poll | Endpoint::StreamPoll => Method::GET,
+            Endpoint::Respond | Endpoint::Disconnect | Endpoint::Hello | Endpoint::StreamSend => {
+                Method::POST
+            }
+        }
+    }
+
+    /// Method used for the endpoint by clients which predate the control endpoints, and sent
+    /// every request to the server's root with the secret in a header
+    pub fn legacy_method(&self) -> Option<Method> {
+        match self {
+            Endpoint::Poll => Some(Method::GET),
+            Endpoint::Respond => Some(Method::POST),
+            Endpoint::Disconnect => Some(Method::DELETE),
+            Endpoint::Hello | Endpoint::StreamPoll | Endpoint::StreamSend => None,
+        }
+    }
+
+    /// The protocol version and endpoint a control path refers to, eg: `/_tunnel/v1/poll`
+    pub fn parse(path: &str) -> Option<(u32, Endpoint)> {
+        let (version, name) = path.strip_prefix(CONTROL_PREFIX)?.split_once('/')?;
+        let version = version.strip_prefix('v')?.parse().ok()?;
+
+        Endpoint::ALL
+            .into_iter()
+            .find(|endpoint| endpoint.name() == name)
+            .map(|endpoint| (version, endpoint))
+    }
+}
+
+/// The newest protocol version both sides speak, if any
+pub fn negotiate(ours: &[u32], theirs: &[u32]) -> Option<u32> {
+    ours.iter()
+        .filter(|version| theirs.contains(version))
+        .max()
+        .copied()
+}
+
+/// Most a compressed control message may expand to, so a tiny gzip bomb can't exhaust memory
+pub const MAX_DECOMPRESSED_MESSAGE: usize = 512 * 1024 * 1024;
+
+/// Compress a control message if that's worthwhile, returning the `content-encoding` used
+pub fn compress(body: Vec<u8>) -> (Vec<u8>, Option<&'static str>) {
+    // Below this, gzip's own header and footer eat most of the savings
+    if body.len() < 1024 {
+        return (body, None);
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    match encoder.write_all(&body).and_then(|_| encoder.finish()) {
+        Ok(compressed) if compressed.len() < body.len() => (compressed, Some("gzip")),
+        _ => (body, None),
+    }
+}
+
+/// Undo `compress`, refusing to produce more than `limit` bytes
+pub fn decompress(body: Vec<u8>, encoding: Option<&str>, limit: usize) -> io::Result<Vec<u8>> {
+    match encoding.map(|encoding| encoding.trim()) {
+        None | Some("identity") => Ok(body),
+        Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(&body[..])
+                .take(limit as u64 + 1)
+                .read_to_end(&mut decompressed)?;
+
+            if decompressed.len() > limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decompressed body is too large",
+                ));
+            }
+
+            Ok(decompressed)
+        }
+        Some(encoding) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported content encoding '{}'", encoding),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Base64Bytes, ClientResponse};
+    use uuid::Uuid;
+
+    #[test]
+    fn builds_and_parses_endpoint_paths() {
+        assert_eq!("/_tunnel/v1/poll", Endpoint::Poll.path(1));
+        assert_eq!(
+            Some((1, Endpoint::Respond)),
+            Endpoint::parse("/_tunnel/v1/respond")
+        );
+        assert_eq!(
+            Some((7, Endpoint::Disconnect)),
+            Endpoint::parse("/_tunnel/v7/disconnect")
+        );
+        assert_eq!("/_tunnel/v1/streams/poll", Endpoint::StreamPoll.path(1));
+        assert_eq!(
+            Some((1, Endpoint::StreamSend)),
+            Endpoint::parse("/_tunnel/v1/streams/send")
+        );
+
+        assert_eq!(None, Endpoint::parse("/_tunnel/v1/unknown"));
+        assert_eq!(None, Endpoint::parse("/_tunnel/1/poll"));
+        assert_eq!(None, Endpoint::parse(VERSIONS_PATH));
+        assert_eq!(None, Endpoint::parse("/v1/poll"));
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        assert_eq!(Some(2), negotiate(&[1, 2], &[1, 2, 3]));
+        assert_eq!(Some(1), negotiate(&[1], &[1, 2]));
+        assert_eq!(None, negotiate(&[1], &[2]));
+    }
+
+    #[test]
+    fn agrees_on_capabilities_both_sides_know() {
+        let theirs: Hello = serde_json::from_str(
+            r#"{"version":1,"capabilities":["compression","websockets","telepathy"]}"#,
+        )
+        .unwrap();
+        assert_eq!(Capability::Unknown, theirs.capabilities[2]);
+
+        assert_eq!(
+            vec![Capability::Compression],
+            agree(&CAPABILITIES, &theirs.capabilities)
+        );
+        assert_eq!(
+            WireFormat::Json,
+            WireFormat::agreed(&agree(&CAPABILITIES, &theirs.capabilities))
+        );
+        assert!(agree(&[Capability::Unknown], &[Capability::Unknown]).is_empty());
+
+        // Clients which predate capabilities just don't list any
+        let bare: Hello = serde_json::from_str(r#"{"version":1}"#).unwrap();
+        assert!(bare.capabilities.is_empty());
+
+        assert_eq!(
+            Ok(Capability::WebSockets),
+            Capability::from_str("WebSockets")
+        );
+        assert!(Capability::from_str("unknown").is_err());
+    }
+
+    #[test]
+    fn carries_raw_bytes_in_message_pack() {
+        let response = ClientResponse {
+            request_id: Uuid::new_v4(),
+            status: 200,
+            headers: vec![(
+                String::from("content-type"),
+                Base64Bytes(b"image/png".to_vec()),
+            )],
+            body: Base64Bytes(vec![0x89, b'P', b'N', b'G', 0, 0xff]),
+        };
+
+        for format in [WireFormat::Json, WireFormat::MessagePack] {
+            let encoded = format.encode(&response);
+            let decoded: ClientResponse = format.decode(&encoded).unwrap();
+
+            assert_eq!(response.request_id, decoded.request_id);
+            assert_eq!(response.body.0, decoded.body.0);
+            assert_eq!(response.headers[0].1 .0, decoded.headers[0].1 .0);
+        }
+
+        // The body isn't base64 encoded
+        let encoded = WireFormat::MessagePack.encode(&response);
+        assert!(encoded
+            .windows(response.body.0.len())
+            .any(|window| window == response.body.0));
+
+        assert!(WireFormat::MessagePack
+            .decode::<ClientResponse>(b"{}")
+            .is_err());
+        assert_eq!(
+            WireFormat::MessagePack,
+            WireFormat::from_content_type(Some("application/msgpack; charset=binary"))
+        );
+        assert_eq!(WireFormat::Json, WireFormat::from_content_type(None));
+    }
+
+    #[test]
+    fn compresses_large_messages_only() {
+        let small = b"{}".to_vec();
+        assert_eq!((small.clone(), None), compress(small));
+
+        let large = vec![b'a'; 4096];
+        let (compressed, encoding) = compress(large.clone());
+        assert_eq!(Some("gzip"), encoding);
+        assert!(compressed.len() < large.len());
+
+        assert_eq!(
+            large,
+            decompress(compressed.clone(), encoding, 4096).unwrap()
+        );
+        assert!(decompress(compressed, encoding, 4095).is_err());
+        assert!(decompress(large, Some("br"), 4096).is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::str::FromStr;
+
+use hyper::header::{HeaderMap, HeaderName};
+use uuid::Uuid;
+
+/// How urgent a request is; higher priorities are handed out first
+pub type Priority = i32;
+
+/// Decides the priority of each visitor request
+#[derive(Clone, Debug, Default)]
+pub struct PriorityRules {
+    /// Path prefixes and the priority of requests under them; the longest match wins
+    pub paths: Vec<(String, Priority)>,
+
+    /// Header from which visitors may pick their own priority, within the range of `paths`
+    pub header: Option<HeaderName>,
+
+    /// Let the header's priority win over a matching path rule, rather than only apply to
+    /// requests no rule covers
+    pub header_overrides: bool,
+}
+
+impl PriorityRules {
+    pub fn priority(&self, path: &str, headers: &HeaderMap) -> Priority {
+        let from_path = self
+            .paths
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, priority)| *priority);
+
+        if from_path.is_some() && !self.header_overrides {
+            return from_path.unwrap_or_default();
+        }
+
+        self.header_priority(headers)
+            .or(from_path)
+            .unwrap_or_default()
+    }
+
+    /// The priority a visitor asked for, kept between the lowest and highest priorities
+    /// handed out by the path rules, so nobody can jump ahead of everything else
+    fn header_priority(&self, headers: &HeaderMap) -> Option<Priority> {
+        let priority = self
+            .header
+            .as_ref()
+            .and_then(|name| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Priority::from_str(value.trim()).ok())?;
+
+        let priorities = self.paths.iter().map(|(_, priority)| *priority);
+        let lowest = priorities.clone().fold(0, Priority::min);
+        let highest = priorities.fold(0, Priority::max);
+
+        Some(priority.clamp(lowest, highest))
+    }
+}
+
+/// Parse a path rule such as `/api/ui=10`
+pub fn parse_path_priority(rule: &str) -> Result<(String, Priority), String> {
+    match rule.rsplit_once('=') {
+        Some((prefix, priority)) if prefix.starts_with('/') => Priority::from_str(priority.trim())
+            .map(|priority| (prefix.to_string(), priority))
+            .map_err(|_| format!("Invalid priority in '{}'", rule)),
+        _ => Err(format!(
+            "Invalid priority rule '{}'; expected /PREFIX=PRIORITY",
+            rule
+        )),
+    }
+}
+
+/// The requests of one sender, at one priority
+struct Lane<K, T> {
+    key: K,
+    entries: VecDeque<(Uuid, T)>,
+}
+
+/// A request queue which hands out higher priority requests first, and takes turns between
+/// senders at the same priority, so that one sender's burst can't hold up everyone else.
+///
+/// Each sender's own requests stay in the order they arrived.
+pub struct FairQueue<K, T> {
+    classes: BTreeMap<Reverse<Priority>, VecDeque<Lane<K, T>>>,
+    len: usize,
+}
+
+impl<K: PartialEq, T> Default for FairQueue<K, T> {
+    fn default() -> Self {
+        FairQueue::new()
+    }
+}
+
+impl<K: PartialEq, T> FairQueue<K, T> {
+    pub fn new() -> FairQueue<K, T> {
+        FairQueue {
+            classes: BTreeMap::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a request behind the sender's earlier requests
+    pub fn push_back(&mut self, id: Uuid, key: K, priority: Priority, item: T) {
+        let lanes = self.classes.entry(Reverse(priority)).or_default();
+
+        match lanes.iter_mut().find(|lane| lane.key == key) {
+            Some(lane) => lane.entries.push_back((id, item)),
+            None => lanes.push_back(Lane {
+                key,
+                entries: VecDeque::from([(id, item)]),
+            }),
+        }
+
+        self.len += 1;
+    }
+
+    /// Queue a request to be handed out before any other at its priority
+    pub fn push_front(&mut self, id: Uuid, key: K, priority: Priority, item: T) {
+        let lanes = self.classes.entry(Reverse(priority)).or_default();
+
+        let lane = match lanes.iter().position(|lane| lane.key == key) {
+            Some(index) => lanes.remove(index).expect("Lane index is in bounds"),
+            None => Lane {
+                key,
+                entries: VecDeque::new(),
+            },
+        };
+
+        lanes.push_front(lane);
+        lanes[0].entries.push_front((id, item));
+        self.len += 1;
+    }
+
+    /// Take the next request, from the next sender in turn at the highest priority
+    pub fn pop_front(&mut self) -> Option<(Uuid, T)> {
+        let mut class = self.classes.first_entry()?;
+        let lanes = class.get_mut();
+
+        let mut lane = lanes.pop_front()?;
+        let entry = lane.entries.pop_front();
+
+        // The sender goes to the back of the line, if they have anything left
+        if !lane.entries.is_empty() {
+            lanes.push_back(lane);
+        }
+        if lanes.is_empty() {
+            class.remove();
+        }
+
+        if entry.is_some() {
+            self.len -= 1;
+        }
+
+        entry
+    }
+
+    /// Remove a queued request, returning whether it was queued
+    pub fn remove(&mut self, id: Uuid) -> bool {
+        for lanes in self.classes.values_mut() {
+            for lane in lanes.iter_mut() {
+                if let Some(index) = lane.entries.iter().position(|(queued, _)| *queued == id) {
+                    lane.entries.remove(index);
+                    lanes.retain(|lane| !lane.entries.is_empty());
+                    self.classes.retain(|_, lanes| !lanes.is_empty());
+                    self.len -= 1;
+                    return true;
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Remove every queued request, returning their IDs in the order they'd have been handed out
+    pub fn drain(&mut self) -> Vec<Uuid> {
+        let mut ids = Vec::with_capacity(self.len);
+
+        while let Some((id, _)) = self.pop_front() {
+            ids.push(id);
+        }
+
+        ids
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(n: usize) -> Vec<Uuid> {
+        (0..n).map(|_| Uuid::new_v4()).collect()
+    }
+
+    #[test]
+    fn takes_turns_between_senders() {
+        let mut queue = FairQueue::new();
+        let id = ids(5);
+
+        // A burst from one sender, then a single request from another
+        for (i, id) in id.iter().take(4).enumerate() {
+            queue.push_back(*id, "batch", 0, i);
+        }
+        queue.push_back(id[4], "interactive", 0, 4);
+        assert_eq!(5, queue.len());
+
+        let order: Vec<usize> = std::iter::from_fn(|| queue.pop_front())
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(vec![0, 4, 1, 2, 3], order);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn hands_out_higher_priorities_first() {
+        let mut queue = FairQueue::new();
+        let id = ids(4);
+
+        queue.push_back(id[0], "a", 0, "normal");
+        queue.push_back(id[1], "a", -5, "low");
+        queue.push_back(id[2], "b", 10, "high");
+        queue.push_front(id[3], "a", 0, "requeued");
+
+        let order: Vec<&str> = std::iter::from_fn(|| queue.pop_front())
+            .map(|(_, item)| item)
+            .collect();
+        assert_eq!(vec!["high", "requeued", "normal", "low"], order);
+    }
+
+    #[test]
+    fn removes_and_drains_requests() {
+        let mut queue = FairQueue::new();
+        let id = ids(3);
+
+        queue.push_back(id[0], "a", 0, ());
+        queue.push_back(id[1], "b", 1, ());
+        queue.push_back(id[2], "a", 0, ());
+
+        assert!(queue.remove(id[1]));
+        assert!(!queue.remove(id[1]));
+        assert_eq!(2, queue.len());
+        assert_eq!(vec![id[0], id[2]], queue.drain());
+        assert!(queue.pop_front().is_none());
+    }
+
+    #[test]
+    fn prioritises_by_path_and_header() {
+        let mut rules = PriorityRules {
+            paths: vec![
+                parse_path_priority("/api=5").unwrap(),
+                parse_path_priority("/api/batch=-10").unwrap(),
+            ],
+            header: Some(HeaderName::from_static("x-priority")),
+            header_overrides: false,
+        };
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(0, rules.priority("/", &headers));
+        assert_eq!(5, rules.priority("/api/users", &headers));
+        assert_eq!(-10, rules.priority("/api/batch/1", &headers));
+
+        // Visitors may only pick a priority for requests no rule covers, within their range
+        headers.insert("x-priority", "3".parse().unwrap());
+        assert_eq!(-10, rules.priority("/api/batch/1", &headers));
+        assert_eq!(3, rules.priority("/", &headers));
+
+        headers.insert("x-priority", "2000000000".parse().unwrap());
+        assert_eq!(5, rules.priority("/", &headers));
+        headers.insert("x-priority", "-2000000000".parse().unwrap());
+        assert_eq!(-10, rules.priority("/", &headers));
+
+        rules.header_overrides = true;
+        headers.insert("x-priority", "3".parse().unwrap());
+        assert_eq!(3, rules.priority("/api/batch/1", &headers));
+
+        assert!(parse_path_priority("api=5").is_err());
+        assert!(parse_path_priority("/api=high").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+/// Once this many keys are being tracked, buckets which have refilled completely are dropped
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How quickly requests may be made: `rate` per second on average, with bursts of up to `burst`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// Why a request was turned away by a `RateLimiter`
+#[derive(Debug, PartialEq)]
+pub struct Limited {
+    /// How long until another request will be allowed
+    pub retry_after: Duration,
+
+    /// How long until the whole burst is available again
+    pub reset_after: Duration,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds a whole token again
+    fn limited(&self, limit: &RateLimit) -> Limited {
+        Limited {
+            retry_after: Duration::from_secs_f64((1.0 - self.tokens) / limit.rate),
+            reset_after: Duration::from_secs_f64((limit.burst - self.tokens) / limit.rate),
+        }
+    }
+}
+
+/// Token-bucket rate limiter, keeping a separate bucket for each key
+pub struct RateLimiter<K> {
+    limit: RateLimit,
+    buckets: HashMap<K, TokenBucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: RateLimit) -> RateLimiter<K> {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimit {
+        &self.limit
+    }
+
+    /// Take a token from `key`'s bucket, returning the number of whole tokens left
+    pub fn check(&mut self, key: K, now: Instant) -> Result<u64, Limited> {
+        let limit = self.limit;
+        let bucket = self.bucket(key, now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(bucket.tokens as u64)
+        } else {
+            Err(bucket.limited(&limit))
+        }
+    }
+
+    /// Whether `key`'s bucket has a token to spare, without taking it
+    pub fn peek(&mut self, key: K, now: Instant) -> Result<(), Limited> {
+        let limit = self.limit;
+        let bucket = self.bucket(key, now);
+
+        if bucket.tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(bucket.limited(&limit))
+        }
+    }
+
+    /// `key`'s bucket, refilled up to `now`
+    fn bucket(&mut self, key: K, now: Instant) -> &mut TokenBucket {
+        if self.buckets.len() >= PRUNE_THRESHOLD && !self.buckets.contains_key(&key) {
+            self.prune(now);
+        }
+
+        let limit = self.limit;
+        let bucket = self.buckets.entry(key).or_insert(TokenBucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+
+        bucket.refill(&limit, now);
+        bucket
+    }
+
+    /// Forget every bucket that has refilled completely; they're equivalent to new ones
+    fn prune(&mut self, now: Instant) {
+        let limit = self.limit;
+
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(&limit, now);
+            bucket.tokens < limit.burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        rate: 2.0,
+        burst: 3.0,
+    };
+
+    #[test]
+    fn allows_bursts_up_to_capacity() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+
+        assert_eq!(Ok(2), limiter.check("a", now));
+        assert_eq!(Ok(1), limiter.check("a", now));
+        assert_eq!(Ok(0), limiter.check("a", now));
+
+        let limited = limiter.check("a", now).unwrap_err();
+        assert_eq!(Duration::from_millis(500), limited.retry_after);
+        assert_eq!(Duration::from_millis(1500), limited.reset_after);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            limiter.check("a", now).unwrap();
+        }
+        assert!(limiter.check("a", now).is_err());
+
+        // Two tokens per second, so one is back after half a second
+        let later = now + Duration::from_millis(500);
+        assert_eq!(Ok(0), limiter.check("a", later));
+        assert!(limiter.check("a", later).is_err());
+
+        // ...but never more than the burst size
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(Ok(2), limiter.check("a", much_later));
+    }
+
+    #[test]
+    fn keys_are_limited_independently() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            limiter.check("a", now).unwrap();
+        }
+
+        assert!(limiter.check("a", now).is_err());
+        assert_eq!(Ok(2), limiter.check("b", now));
+    }
+
+    #[test]
+    fn peeking_takes_no_tokens() {
+        let mut limiter = RateLimiter::new(LIMIT);
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            assert_eq!(Ok(()), limiter.peek("a", now));
+        }
+        assert_eq!(Ok(2), limiter.check("a", now));
+
+        limiter.check("a", now).unwrap();
+        limiter.check("a", now).unwrap();
+        assert_eq!(
+            Duration::from_millis(500),
+            limiter.peek("a", now).unwrap_err().retry_after
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use std::io::{Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use url::Url;
+
+/// The scheme, host and port of a URL, eg: `https://example.test:8443`
+pub fn origin(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+/// Point a `Location` header at `to` instead of `from`, if it refers to `from` at all.
+///
+/// Relative locations are left alone, since they already work through the tunnel.
+pub fn rewrite_location(location: &str, from: &Url, to: &Url) -> Option<String> {
+    let target = Url::parse(location).ok()?;
+
+    if target.origin() != from.origin() {
+        return None;
+    }
+
+    let from_origin = origin(from);
+    let rest = location.get(from_origin.len()..).filter(|rest| {
+        location
+            .get(..from_origin.len())
+            .is_some_and(|o| o.eq_ignore_ascii_case(&from_origin))
+            && (rest.is_empty() || rest.starts_with(['/', '?', '#']))
+    });
+
+    match rest {
+        Some(rest) => Some(format!("{}{}", origin(to), rest)),
+
+        // The location spells the origin differently, eg: with an explicit default port
+        None => {
+            let mut rewritten = target;
+            rewritten.set_scheme(to.scheme()).ok()?;
+            rewritten.set_host(to.host_str()).ok()?;
+            rewritten.set_port(to.port()).ok()?;
+            Some(rewritten.to_string())
+        }
+    }
+}
+
+/// Replace the `Domain` attribute of a `Set-Cookie` header, if it names `from_host`
+pub fn rewrite_cookie_domain(cookie: &str, from_host: &str, to_host: &str) -> Option<String> {
+    let mut changed = false;
+
+    let attributes: Vec<String> = cookie
+        .split(';')
+        .map(|attribute| {
+            let trimmed = attribute.trim();
+
+            match trimmed.split_once('=') {
+                Some((name, domain))
+                    if name.trim().eq_ignore_ascii_case("domain")
+                        && domain
+                            .trim()
+                            .trim_start_matches('.')
+                            .eq_ignore_ascii_case(from_host) =>
+                {
+                    changed = true;
+                    format!(" Domain={}", to_host)
+                }
+                _ => attribute.to_string(),
+            }
+        })
+        .collect();
+
+    if changed {
+        Some(attributes.join(";"))
+    } else {
+        None
+    }
+}
+
+/// Whether a body of this `Content-Type` is text which may contain links
+pub fn is_text_content_type(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
+/// Replace every occurrence of the origin `from` with `to` in a text body, decoding and
+/// re-encoding it according to its `Content-Encoding` first.
+///
+/// Returns `None` if the body doesn't mention `from`, or its encoding isn't supported.
+pub fn rewrite_body(
+    body: &[u8],
+    content_encoding: Option<&str>,
+    from: &str,
+    to: &str,
+) -> Option<Vec<u8>> {
+    let encoding = content_encoding
+        .map(|e| e.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    let decoded = match encoding.as_str() {
+        "" | "identity" => body.to_vec(),
+        "gzip" | "x-gzip" => {
+            let mut decoded = Vec::new();
+            GzDecoder::new(body).read_to_end(&mut decoded).ok()?;
+            decoded
+        }
+        "br" => {
+            let mut decoded = Vec::new();
+            brotli::Decompressor::new(body, 4096)
+                .read_to_end(&mut decoded)
+                .ok()?;
+            decoded
+        }
+        _ => return None,
+    };
+
+    // JSON may escape the slashes in URLs
+    let escaped = |origin: &str| origin.replace('/', "\\/");
+
+    let rewritten = replace_all(&decoded, from.as_bytes(), to.as_bytes());
+    let rewritten = match replace_all(
+        rewritten.as_deref().unwrap_or(&decoded),
+        escaped(from).as_bytes(),
+        escaped(to).as_bytes(),
+    ) {
+        Some(rewritten) => rewritten,
+        None => rewritten?,
+    };
+
+    match encoding.as_str() {
+        "gzip" | "x-gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&rewritten).ok()?;
+            encoder.finish().ok()
+        }
+        "br" => {
+            let mut encoded = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                encoder.write_all(&rewritten).ok()?;
+            }
+            Some(encoded)
+        }
+        _ => Some(rewritten),
+    }
+}
+
+/// Whether an origin found in a body ends just before `rest`, rather than continuing into a
+/// longer host name or port, like `http://localhost.corp` or `http://localhost:3000` do for
+/// `http://localhost`
+fn ends_origin(rest: &[u8]) -> bool {
+    match rest.first() {
+        None => true,
+        Some(b'/' | b'?' | b'#' | b'"' | b'\'' | b'<') => true,
+        Some(c) if c.is_ascii_whitespace() => true,
+
+        // The escaped slash of a path in JSON
+        Some(b'\\') => rest.get(1) == Some(&b'/'),
+        Some(_) => false,
+    }
+}
+
+/// Replace every occurrence of the origin `needle`, or return `None` if there aren't any
+fn replace_all(haystack: &[u8], needle: &[u8], replacement: &[u8]) -> Option<Vec<u8>> {
+    if needle.is_empty() {
+        return None;
+    }
+
+    let mut output = Vec::with_capacity(haystack.len());
+    let mut rest = haystack;
+    let mut found = false;
+
+    while let Some(index) = rest.windows(needle.len()).position(|w| w == needle) {
+        let (before, after) = (&rest[..index], &rest[index + needle.len()..]);
+        output.extend_from_slice(before);
+
+        if ends_origin(after) {
+            found = true;
+            output.extend_from_slice(replacement);
+        } else {
+            output.extend_from_slice(needle);
+        }
+
+        rest = after;
+    }
+
+    if !found {
+        return None;
+    }
+
+    output.extend_from_slice(rest);
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn url(s: &str) -> Url {
+        Url::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn rewrites_locations_on_the_upstream() {
+        let from = url("http://localhost:8080/");
+        let to = url("https://tunnel.example.test/");
+
+        assert_eq!(
+            Some("https://tunnel.example.test/login?next=%2F".to_string()),
+            rewrite_location("http://localhost:8080/login?next=%2F", &from, &to)
+        );
+        assert_eq!(
+            Some("https://tunnel.example.test".to_string()),
+            rewrite_location("http://localhost:8080", &from, &to)
+        );
+        assert_eq!(None, rewrite_location("/login", &from, &to));
+        assert_eq!(
+            None,
+            rewrite_location("https://elsewhere.test/", &from, &to)
+        );
+
+        let from = url("https://internal.test/");
+        assert_eq!(
+            Some("https://tunnel.example.test/a".to_string()),
+            rewrite_location("https://internal.test:443/a", &from, &to)
+        );
+    }
+
+    #[test]
+    fn rewrites_cookie_domains() {
+        assert_eq!(
+            Some("sid=abc; Path=/; Domain=tunnel.example.test; HttpOnly".to_string()),
+            rewrite_cookie_domain(
+                "sid=abc; Path=/; Domain=.internal.test; HttpOnly",
+                "internal.test",
+                "tunnel.example.test"
+            )
+        );
+        assert_eq!(
+            None,
+            rewrite_cookie_domain("sid=abc; Domain=other.test", "internal.test", "x.test")
+        );
+        assert_eq!(
+            None,
+            rewrite_cookie_domain("sid=abc; Path=/", "internal.test", "x.test")
+        );
+    }
+
+    #[test]
+    fn recognises_text_content_types() {
+        assert!(is_text_content_type("text/html; charset=utf-8"));
+        assert!(is_text_content_type("application/json"));
+        assert!(is_text_content_type("application/ld+json"));
+        assert!(is_text_content_type("image/svg+xml"));
+        assert!(!is_text_content_type("image/png"));
+        assert!(!is_text_content_type("application/octet-stream"));
+    }
+
+    #[test]
+    fn rewrites_plain_and_compressed_bodies() {
+        let from = "http://localhost:8080";
+        let to = "https://tunnel.example.test";
+        let html = br#"<a href="http://localhost:8080/a">a</a> {"b":"http:\/\/localhost:8080\/b"}"#;
+        let expected =
+            br#"<a href="https://tunnel.example.test/a">a</a> {"b":"https:\/\/tunnel.example.test\/b"}"#;
+
+        assert_eq!(Some(expected.to_vec()), rewrite_body(html, None, from, to));
+        assert_eq!(None, rewrite_body(b"nothing to see", None, from, to));
+        assert_eq!(None, rewrite_body(html, Some("zstd"), from, to));
+
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped.write_all(html).unwrap();
+        let rewritten = rewrite_body(&gzipped.finish().unwrap(), Some("gzip"), from, to).unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(&rewritten[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(expected.to_vec(), decoded);
+
+        let mut brotlied = Vec::new();
+        brotli::CompressorWriter::new(&mut brotlied, 4096, 5, 22)
+            .write_all(html)
+            .unwrap();
+        let rewritten = rewrite_body(&brotlied, Some("br"), from, to).unwrap();
+        let mut decoded = Vec::new();
+        brotli::Decompressor::new(&rewritten[..], 4096)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(expected.to_vec(), decoded);
+    }
+
+    #[test]
+    fn leaves_other_hosts_and_ports_alone() {
+        let from = "http://localhost";
+        let to = "https://tunnel.example.test";
+        let body =
+            br#"<a href="http://localhost:3000/a">a</a> <a href="http://localhost.corp/b">b</a>"#;
+
+        assert_eq!(None, rewrite_body(body, None, from, to));
+
+        let body = br#"<a href="http://localhost.corp/b">http://localhost</a> 'http://localhost'"#;
+        let expected = br#"<a href="http://localhost.corp/b">https://tunnel.example.test</a> 'https://tunnel.example.test'"#;
+        assert_eq!(Some(expected.to_vec()), rewrite_body(body, None, from, to));
+    }
+}
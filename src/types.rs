@@ -2,6 +2,7 @@ use base64::{engine::general_purpose as b64, Engine};
 use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
 use serde::ser::{Serialize, Serializer};
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::str::FromStr;
 use uuid::Uuid;
 use void::Void;
@@ -12,7 +13,9 @@ use hyper::StatusCode;
 type HeaderPair = (String, Base64Bytes<Vec<u8>>);
 type HeaderTransportContainer = Vec<HeaderPair>;
 
-/// Wraps a type that may be expressed as a byte slice,
+/// Wraps a type that may be expressed as a byte slice, which is encoded as base64 in
+/// human-readable formats like JSON, and kept as raw bytes in binary ones like MessagePack.
+#[derive(Clone, Debug, PartialEq)]
 pub struct Base64Bytes<T: ?Sized + AsRef<[u8]>>(pub T);
 
 impl Base64Bytes<Vec<u8>> {
@@ -27,7 +30,11 @@ impl<T: ?Sized + AsRef<[u8]>> Serialize for Base64Bytes<T> {
     where
         S: Serializer,
     {
-        b64::STANDARD.encode(&self.0).serialize(serializer)
+        if serializer.is_human_readable() {
+            b64::STANDARD.encode(&self.0).serialize(serializer)
+        } else {
+            serializer.serialize_bytes(self.0.as_ref())
+        }
     }
 }
 
@@ -36,9 +43,40 @@ impl<'de> Deserialize<'de> for Base64Bytes<Vec<u8>> {
     where
         D: Deserializer<'de>,
     {
-        deserializer
-            .deserialize_string(Base64Visitor::<Vec<u8>>::new())
-            .map(|d| Base64Bytes(d))
+        if deserializer.is_human_readable() {
+            deserializer
+                .deserialize_string(Base64Visitor::<Vec<u8>>::new())
+                .map(Base64Bytes)
+        } else {
+            deserializer
+                .deserialize_byte_buf(RawBytesVisitor)
+                .map(Base64Bytes)
+        }
+    }
+}
+
+/// Visitor struct for deserializing raw bytes from binary formats using Serde
+struct RawBytesVisitor;
+
+impl<'de> Visitor<'de> for RawBytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        formatter.write_str("a byte array")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(v)
     }
 }
 
@@ -91,6 +129,18 @@ pub struct ProxiedRequest<'a> {
     pub headers: Vec<(&'a str, Base64Bytes<Vec<u8>>)>,
     pub body: Base64Bytes<Vec<u8>>,
     pub id: Uuid,
+
+    /// Address of the visitor who sent the request, as seen by the server
+    #[serde(default)]
+    pub remote_addr: Option<IpAddr>,
+
+    /// Scheme with which the visitor reached the server
+    #[serde(default)]
+    pub scheme: Option<String>,
+
+    /// `Host` the visitor sent to the server
+    #[serde(default)]
+    pub host: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -109,6 +159,43 @@ pub struct ClientResponse {
     pub body: Base64Bytes<Vec<u8>>,
 }
 
+/// Something that happened to a raw TCP connection carried through the tunnel.
+///
+/// The server tells the client about connections visitors opened, and both sides pass on the
+/// bytes they read and when their end was closed; closing either end closes the whole thing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StreamEvent {
+    /// A visitor connected to the server's TCP port; only sent by the server
+    Open {
+        id: Uuid,
+
+        /// Address of the visitor who connected, as seen by the server
+        #[serde(default)]
+        remote_addr: Option<IpAddr>,
+    },
+
+    /// Bytes read from one end of the connection, for the other
+    Data {
+        id: Uuid,
+        data: Base64Bytes<Vec<u8>>,
+    },
+
+    /// One end of the connection was closed
+    Close { id: Uuid },
+}
+
+impl StreamEvent {
+    /// The connection the event concerns
+    pub fn id(&self) -> Uuid {
+        match self {
+            StreamEvent::Open { id, .. }
+            | StreamEvent::Data { id, .. }
+            | StreamEvent::Close { id } => *id,
+        }
+    }
+}
+
 impl ClientResponse {
     pub fn headers(&self) -> HeaderMap {
         Self::construct_header_map(&self.headers)
@@ -127,7 +214,7 @@ impl ClientResponse {
 
     pub fn construct_header_map(headers: &HeaderTransportContainer) -> HeaderMap {
         headers.iter()
-        .fold(HeaderMap::new(), |mut headers, &(ref k, ref v)| {
+        .fold(HeaderMap::new(), |mut headers, (k, v)| {
             let name_bytes: &[u8] = k.as_ref();
             let value_bytes: &[u8] = v.0.as_ref();
 
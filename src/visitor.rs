@@ -0,0 +1,101 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::header::HeaderMap;
+
+/// Where to find the real address of a visitor when the server sits behind a reverse proxy
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrustedIpHeader {
+    /// The server is reached directly; only trust the address of the connection
+    None,
+
+    /// Use the last address in `X-Forwarded-For`, which is the one our proxy appended
+    XForwardedFor,
+
+    /// Use a header containing a single address, such as `Fly-Client-IP`
+    Single(String),
+}
+
+impl FromStr for TrustedIpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "none" => Ok(TrustedIpHeader::None),
+            "x-forwarded-for" => Ok(TrustedIpHeader::XForwardedFor),
+            name => hyper::header::HeaderName::from_str(name)
+                .map(|_| TrustedIpHeader::Single(name.to_string()))
+                .map_err(|e| format!("Invalid header name '{}': {}", name, e)),
+        }
+    }
+}
+
+/// Determine the IP address of the visitor who sent a request.
+///
+/// Falls back to the address of the connection if the trusted header is missing or invalid.
+pub fn visitor_ip(peer: SocketAddr, headers: &HeaderMap, trusted: &TrustedIpHeader) -> IpAddr {
+    let forwarded = match trusted {
+        TrustedIpHeader::None => None,
+        TrustedIpHeader::XForwardedFor => headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next()),
+        TrustedIpHeader::Single(name) => headers.get(name).and_then(|h| h.to_str().ok()),
+    };
+
+    forwarded
+        .and_then(|ip| IpAddr::from_str(ip.trim()).ok())
+        .unwrap_or_else(|| peer.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], 4321))
+    }
+
+    #[test]
+    fn ignores_headers_unless_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7".parse().unwrap());
+
+        assert_eq!(
+            peer().ip(),
+            visitor_ip(peer(), &headers, &TrustedIpHeader::None)
+        );
+    }
+
+    #[test]
+    fn uses_last_forwarded_for_address() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append(
+            "x-forwarded-for",
+            "198.51.100.4, 203.0.113.7".parse().unwrap(),
+        );
+
+        assert_eq!(
+            IpAddr::from([203, 0, 113, 7]),
+            visitor_ip(peer(), &headers, &TrustedIpHeader::XForwardedFor)
+        );
+    }
+
+    #[test]
+    fn uses_single_address_header() {
+        let trusted = TrustedIpHeader::from_str("Fly-Client-IP").unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("fly-client-ip", "2001:db8::1".parse().unwrap());
+        assert_eq!(
+            IpAddr::from_str("2001:db8::1").unwrap(),
+            visitor_ip(peer(), &headers, &trusted)
+        );
+
+        headers.insert("fly-client-ip", "garbage".parse().unwrap());
+        assert_eq!(peer().ip(), visitor_ip(peer(), &headers, &trusted));
+    }
+}
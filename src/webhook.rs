@@ -0,0 +1,418 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use hyper::header::HeaderMap;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+/// Hash function used for the HMAC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "sha512" => Ok(Algorithm::Sha512),
+            other => Err(format!("Unsupported algorithm '{}'", other)),
+        }
+    }
+}
+
+/// How the signature is written in the header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Hex,
+    Base64,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            other => Err(format!("Unsupported encoding '{}'", other)),
+        }
+    }
+}
+
+/// Piece of the payload which is signed
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Body,
+    Timestamp,
+    Header(String),
+}
+
+/// The exact bytes a provider signs, written as a template such as `{timestamp}.{body}`.
+///
+/// `{body}` is the raw request body, `{timestamp}` the request's timestamp, and
+/// `{header:Some-Name}` the value of a request header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadFormat(Vec<Segment>);
+
+impl FromStr for PayloadFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = s;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("Unclosed '{{' in payload format '{}'", s))?;
+
+            segments.push(match &rest[start + 1..end] {
+                "body" => Segment::Body,
+                "timestamp" => Segment::Timestamp,
+                name if name.starts_with("header:") => {
+                    Segment::Header(name["header:".len()..].to_ascii_lowercase())
+                }
+                other => return Err(format!("Unknown placeholder '{{{}}}'", other)),
+            });
+
+            rest = &rest[end + 1..];
+        }
+
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(PayloadFormat(segments))
+    }
+}
+
+/// Why a webhook was rejected
+#[derive(Debug, PartialEq)]
+pub enum WebhookError {
+    MissingSignature,
+    MalformedSignature,
+    MissingTimestamp,
+    StaleTimestamp,
+    MissingHeader(String),
+    InvalidSignature,
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebhookError::MissingSignature => write!(f, "signature header is missing"),
+            WebhookError::MalformedSignature => write!(f, "signature header is malformed"),
+            WebhookError::MissingTimestamp => write!(f, "timestamp is missing"),
+            WebhookError::StaleTimestamp => write!(f, "timestamp is outside the tolerance"),
+            WebhookError::MissingHeader(name) => write!(f, "signed header '{}' is missing", name),
+            WebhookError::InvalidSignature => write!(f, "signature doesn't match"),
+        }
+    }
+}
+
+/// Verifies HMAC signatures on incoming webhooks
+#[derive(Clone, Debug)]
+pub struct WebhookVerifier {
+    pub secret: String,
+
+    /// Header carrying the signature
+    pub header: String,
+    pub algorithm: Algorithm,
+    pub encoding: Encoding,
+
+    /// Text in front of the signature which isn't part of it, eg: `sha256=`
+    pub prefix: String,
+
+    pub payload: PayloadFormat,
+
+    /// Header carrying the timestamp, if it isn't part of the signature header
+    pub timestamp_header: Option<String>,
+
+    /// Whether the signature header is a Stripe-style `t=<timestamp>,v1=<signature>` list
+    pub keyed_signature: bool,
+
+    /// How far the timestamp may be from the current time; `None` doesn't check it
+    pub tolerance: Option<Duration>,
+}
+
+impl WebhookVerifier {
+    /// Settings for a provider's webhooks, or a custom verifier signing the body with SHA-256.
+    ///
+    /// The `custom` preset still needs its `header` to be set.
+    pub fn preset(name: &str, secret: String) -> Result<WebhookVerifier, String> {
+        let custom = WebhookVerifier {
+            secret,
+            header: String::new(),
+            algorithm: Algorithm::Sha256,
+            encoding: Encoding::Hex,
+            prefix: String::new(),
+            payload: PayloadFormat(vec![Segment::Body]),
+            timestamp_header: None,
+            keyed_signature: false,
+            tolerance: None,
+        };
+
+        let five_minutes = Some(Duration::from_secs(5 * 60));
+
+        match name.to_ascii_lowercase().as_str() {
+            "custom" => Ok(custom),
+            "github" => Ok(WebhookVerifier {
+                header: "x-hub-signature-256".into(),
+                prefix: "sha256=".into(),
+                ..custom
+            }),
+            "stripe" => Ok(WebhookVerifier {
+                header: "stripe-signature".into(),
+                payload: PayloadFormat::from_str("{timestamp}.{body}")?,
+                keyed_signature: true,
+                tolerance: five_minutes,
+                ..custom
+            }),
+            "slack" => Ok(WebhookVerifier {
+                header: "x-slack-signature".into(),
+                prefix: "v0=".into(),
+                payload: PayloadFormat::from_str("v0:{timestamp}:{body}")?,
+                timestamp_header: Some("x-slack-request-timestamp".into()),
+                tolerance: five_minutes,
+                ..custom
+            }),
+            "shopify" => Ok(WebhookVerifier {
+                header: "x-shopify-hmac-sha256".into(),
+                encoding: Encoding::Base64,
+                ..custom
+            }),
+            other => Err(format!("Unknown webhook preset '{}'", other)),
+        }
+    }
+
+    /// Check the signature of a request with the given headers and body
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: SystemTime,
+    ) -> Result<(), WebhookError> {
+        let signature_header = headers
+            .get(self.header.as_str())
+            .and_then(|h| h.to_str().ok())
+            .ok_or(WebhookError::MissingSignature)?;
+
+        let (signatures, timestamp) = if self.keyed_signature {
+            let mut signatures = Vec::new();
+            let mut timestamp = None;
+
+            for (key, value) in signature_header
+                .split(',')
+                .filter_map(|pair| pair.trim().split_once('='))
+            {
+                match key {
+                    "t" => timestamp = Some(value),
+                    "v1" => signatures.push(value),
+                    _ => {}
+                }
+            }
+
+            (signatures, timestamp)
+        } else {
+            let signature = signature_header
+                .trim()
+                .strip_prefix(self.prefix.as_str())
+                .ok_or(WebhookError::MalformedSignature)?;
+
+            let timestamp = match &self.timestamp_header {
+                Some(name) => headers.get(name.as_str()).and_then(|h| h.to_str().ok()),
+                None => None,
+            };
+
+            (vec![signature], timestamp)
+        };
+
+        if signatures.is_empty() {
+            return Err(WebhookError::MalformedSignature);
+        }
+
+        let needs_timestamp =
+            self.tolerance.is_some() || self.payload.0.contains(&Segment::Timestamp);
+        let timestamp = timestamp.map(|t| t.trim());
+
+        if needs_timestamp && timestamp.is_none() {
+            return Err(WebhookError::MissingTimestamp);
+        }
+
+        if let (Some(tolerance), Some(timestamp)) = (self.tolerance, timestamp) {
+            let timestamp = u64::from_str(timestamp).map_err(|_| WebhookError::MissingTimestamp)?;
+            let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+            if now.abs_diff(timestamp) > tolerance.as_secs() {
+                return Err(WebhookError::StaleTimestamp);
+            }
+        }
+
+        let mut payload = Vec::with_capacity(body.len());
+        for segment in &self.payload.0 {
+            match segment {
+                Segment::Literal(text) => payload.extend_from_slice(text.as_bytes()),
+                Segment::Body => payload.extend_from_slice(body),
+                Segment::Timestamp => payload.extend_from_slice(timestamp.unwrap_or("").as_bytes()),
+                Segment::Header(name) => payload.extend_from_slice(
+                    headers
+                        .get(name.as_str())
+                        .ok_or_else(|| WebhookError::MissingHeader(name.clone()))?
+                        .as_bytes(),
+                ),
+            }
+        }
+
+        let matches = signatures.iter().any(|signature| {
+            let signature = match self.encoding {
+                Encoding::Hex => hex::decode(signature).ok(),
+                Encoding::Base64 => general_purpose::STANDARD.decode(signature).ok(),
+            };
+
+            match signature {
+                Some(signature) => self.mac_matches(&payload, &signature),
+                None => false,
+            }
+        });
+
+        if matches {
+            Ok(())
+        } else {
+            Err(WebhookError::InvalidSignature)
+        }
+    }
+
+    /// Compare the HMAC of the payload with the signature, in constant time
+    fn mac_matches(&self, payload: &[u8], signature: &[u8]) -> bool {
+        fn verify<M: Mac + hmac::digest::KeyInit>(key: &[u8], payload: &[u8], sig: &[u8]) -> bool {
+            let mut mac = <M as Mac>::new_from_slice(key).expect("HMAC accepts keys of any size");
+            mac.update(payload);
+            mac.verify_slice(sig).is_ok()
+        }
+
+        let key = self.secret.as_bytes();
+
+        match self.algorithm {
+            Algorithm::Sha1 => verify::<Hmac<Sha1>>(key, payload, signature),
+            Algorithm::Sha256 => verify::<Hmac<Sha256>>(key, payload, signature),
+            Algorithm::Sha512 => verify::<Hmac<Sha512>>(key, payload, signature),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn verifies_github_signatures() {
+        // Example from GitHub's documentation
+        let verifier =
+            WebhookVerifier::preset("github", "It's a Secret to Everybody".into()).unwrap();
+        let valid = headers(&[(
+            "x-hub-signature-256",
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17",
+        )]);
+
+        assert_eq!(Ok(()), verifier.verify(&valid, b"Hello, World!", at(0)));
+        assert_eq!(
+            Err(WebhookError::InvalidSignature),
+            verifier.verify(&valid, b"Hello, World?", at(0))
+        );
+        assert_eq!(
+            Err(WebhookError::MissingSignature),
+            verifier.verify(&HeaderMap::new(), b"Hello, World!", at(0))
+        );
+    }
+
+    #[test]
+    fn verifies_stripe_signatures() {
+        let verifier = WebhookVerifier::preset("stripe", "whsec_test".into()).unwrap();
+        let body = br#"{"id":"evt_1"}"#;
+        let valid = headers(&[(
+            "stripe-signature",
+            "t=1700000000,v1=0000,v1=c89214b5b5da833daed6f0b8c5bb6bd58cea9022bd80ccc78230f3942d632925",
+        )]);
+
+        assert_eq!(Ok(()), verifier.verify(&valid, body, at(1_700_000_060)));
+        assert_eq!(
+            Err(WebhookError::StaleTimestamp),
+            verifier.verify(&valid, body, at(1_700_000_000 + 301))
+        );
+
+        let replayed = headers(&[(
+            "stripe-signature",
+            "t=1700000001,v1=c89214b5b5da833daed6f0b8c5bb6bd58cea9022bd80ccc78230f3942d632925",
+        )]);
+        assert_eq!(
+            Err(WebhookError::InvalidSignature),
+            verifier.verify(&replayed, body, at(1_700_000_000))
+        );
+    }
+
+    #[test]
+    fn verifies_slack_and_shopify_signatures() {
+        let slack = WebhookVerifier::preset("slack", "slacksecret".into()).unwrap();
+        let valid = headers(&[
+            (
+                "x-slack-signature",
+                "v0=7cf0b94f84fa0a7e79520f0e55d39154d1299afcc543a7e1e91cdd130ee0fb53",
+            ),
+            ("x-slack-request-timestamp", "1700000000"),
+        ]);
+        assert_eq!(
+            Ok(()),
+            slack.verify(&valid, b"token=abc", at(1_700_000_000))
+        );
+
+        let shopify = WebhookVerifier::preset("shopify", "shpss".into()).unwrap();
+        let valid = headers(&[(
+            "x-shopify-hmac-sha256",
+            "hFJO6VJKe4kBKbdqB4Y0kbIIEQ8QBuyXm5SaI/IGi9o=",
+        )]);
+        assert_eq!(Ok(()), shopify.verify(&valid, br#"{"id":1}"#, at(0)));
+    }
+
+    #[test]
+    fn parses_payload_formats() {
+        assert_eq!(
+            PayloadFormat(vec![
+                Segment::Literal("v0:".into()),
+                Segment::Header("x-request-id".into()),
+                Segment::Literal(":".into()),
+                Segment::Body,
+            ]),
+            PayloadFormat::from_str("v0:{header:X-Request-Id}:{body}").unwrap()
+        );
+
+        assert!(PayloadFormat::from_str("{body").is_err());
+        assert!(PayloadFormat::from_str("{nope}").is_err());
+    }
+}